@@ -19,6 +19,7 @@ public class RatholeClient {
     private final String serverAddr;
     private final int serverPort;
     private final int localPort;
+    private final String token;
 
     private Socket controlSocket;
     private DataInputStream controlInput;
@@ -26,15 +27,25 @@ public class RatholeClient {
     private final Gson gson;
 
     private int assignedPort = -1;
+    private String session;
     private volatile boolean running = false;
     private final ExecutorService executorService;
 
     private static final int HEARTBEAT_INTERVAL_MS = 20000; // 20 seconds
+    private static final int PROTOCOL_VERSION = 1;
 
     public RatholeClient(String serverAddr, int serverPort, int localPort) {
+        this(serverAddr, serverPort, localPort, null);
+    }
+
+    /**
+     * @param token shared token, required when the server has authentication enabled
+     */
+    public RatholeClient(String serverAddr, int serverPort, int localPort, String token) {
         this.serverAddr = serverAddr;
         this.serverPort = serverPort;
         this.localPort = localPort;
+        this.token = token;
         this.gson = new Gson();
         this.executorService = Executors.newCachedThreadPool();
     }
@@ -45,20 +56,35 @@ public class RatholeClient {
     public void start() throws IOException {
         System.out.println("Connecting to " + serverAddr + ":" + serverPort + "...");
 
-        // Connect to server
-        controlSocket = new Socket(serverAddr, serverPort);
-        controlInput = new DataInputStream(controlSocket.getInputStream());
-        controlOutput = new DataOutputStream(controlSocket.getOutputStream());
+        // Connect to server and exchange protocol versions
+        connectControl();
+        if (!hello()) {
+            // Servers older than the Hello handshake close the connection; retry without it
+            System.out.println("Server does not support Hello, assuming protocol version " + PROTOCOL_VERSION);
+            closeQuietly(controlSocket);
+            connectControl();
+        }
 
         // Send TunnelRequest
         Message tunnelRequest = new TunnelRequest(localPort);
         sendMessage(tunnelRequest);
         System.out.println("Sent TunnelRequest for local port " + localPort);
 
-        // Receive TunnelResponse
+        // Receive TunnelResponse (preceded by AuthChallenge when the server requires a token)
         Message response = receiveMessage();
-        if (response instanceof TunnelResponse) {
-            assignedPort = ((TunnelResponse) response).assignedPort;
+        if (response instanceof AuthChallenge) {
+            if (token == null) {
+                throw new IOException("Server requires authentication but no token is configured");
+            }
+            sendMessage(new AuthResponse(hmacSha256Hex(token, ((AuthChallenge) response).nonce)));
+            response = receiveMessage();
+        }
+
+        if (response instanceof TunnelRejected) {
+            throw new IOException("Tunnel rejected by server: " + ((TunnelRejected) response).reason);
+        } else if (response instanceof TunnelResponse) {
+            assignedPort = ((TunnelResponse) response).assigned_port;
+            session = ((TunnelResponse) response).session;
             System.out.println("✅ Tunnel established! Remote port: " + assignedPort);
             System.out.println("Access your service at: " + serverAddr + ":" + assignedPort);
         } else {
@@ -74,6 +100,43 @@ public class RatholeClient {
         executorService.submit(this::sendHeartbeats);
     }
 
+    /**
+     * Open the control connection
+     */
+    private void connectControl() throws IOException {
+        controlSocket = new Socket(serverAddr, serverPort);
+        controlInput = new DataInputStream(controlSocket.getInputStream());
+        controlOutput = new DataOutputStream(controlSocket.getOutputStream());
+    }
+
+    /**
+     * Send Hello and check the server's answer
+     *
+     * @return false if the server closed the connection (it predates the Hello handshake)
+     */
+    private boolean hello() throws IOException {
+        sendMessage(new Hello(PROTOCOL_VERSION, new String[0]));
+
+        Message response;
+        try {
+            response = receiveMessage();
+        } catch (EOFException | SocketException e) {
+            return false;
+        }
+
+        if (response instanceof TunnelRejected) {
+            throw new IOException("Tunnel rejected by server: " + ((TunnelRejected) response).reason);
+        } else if (!(response instanceof Hello)) {
+            throw new IOException("Expected Hello, got: " + response.type);
+        }
+        int version = ((Hello) response).version;
+        if (version != PROTOCOL_VERSION) {
+            throw new IOException("Server speaks protocol version " + version
+                    + ", this client speaks " + PROTOCOL_VERSION);
+        }
+        return true;
+    }
+
     /**
      * Handle control channel messages
      */
@@ -83,11 +146,15 @@ public class RatholeClient {
                 Message msg = receiveMessage();
 
                 if (msg instanceof CreateDataChannel) {
-                    System.out.println("📡 Server requested data channel");
-                    executorService.submit(this::createDataChannel);
+                    long visitorId = ((CreateDataChannel) msg).visitor_id;
+                    System.out.println("📡 Server requested data channel for visitor " + visitorId);
+                    executorService.submit(() -> createDataChannel(visitorId));
                 } else if (msg instanceof Heartbeat) {
                     System.out.println("💓 Received heartbeat");
                     sendMessage(new Heartbeat());
+                } else if (msg instanceof Unknown) {
+                    // Sent by a newer server; ignore it
+                    System.out.println("Ignoring unknown message: " + msg.type);
                 }
             }
         } catch (IOException e) {
@@ -121,7 +188,7 @@ public class RatholeClient {
     /**
      * Create a data channel for forwarding traffic
      */
-    private void createDataChannel() {
+    private void createDataChannel(long visitorId) {
         Socket serverSocket = null;
         Socket localSocket = null;
 
@@ -130,6 +197,10 @@ public class RatholeClient {
             serverSocket = new Socket(serverAddr, serverPort);
             System.out.println("✅ Data channel connected to server");
 
+            // Identify the data channel by session token and visitor ID (source address is not used)
+            writeMessage(new DataOutputStream(serverSocket.getOutputStream()),
+                    new DataChannelHello(session, newNonce(), visitorId));
+
             // Connect to local service
             localSocket = new Socket("127.0.0.1", localPort);
             System.out.println("✅ Data channel connected to local service at port " + localPort);
@@ -188,6 +259,13 @@ public class RatholeClient {
      * Send a message to the server
      */
     private synchronized void sendMessage(Message msg) throws IOException {
+        writeMessage(controlOutput, msg);
+    }
+
+    /**
+     * Write a message to the given stream
+     */
+    private void writeMessage(DataOutputStream output, Message msg) throws IOException {
         String json = gson.toJson(msg);
         byte[] data = json.getBytes("UTF-8");
 
@@ -195,11 +273,42 @@ public class RatholeClient {
         ByteBuffer lengthBuffer = ByteBuffer.allocate(4);
         lengthBuffer.order(ByteOrder.LITTLE_ENDIAN);
         lengthBuffer.putInt(data.length);
-        controlOutput.write(lengthBuffer.array());
+        output.write(lengthBuffer.array());
 
         // Write JSON data
-        controlOutput.write(data);
-        controlOutput.flush();
+        output.write(data);
+        output.flush();
+    }
+
+    /**
+     * HMAC-SHA256(token, nonce) as lowercase hex
+     */
+    private static String hmacSha256Hex(String token, String nonce) throws IOException {
+        try {
+            javax.crypto.Mac mac = javax.crypto.Mac.getInstance("HmacSHA256");
+            mac.init(new javax.crypto.spec.SecretKeySpec(token.getBytes("UTF-8"), "HmacSHA256"));
+            byte[] digest = mac.doFinal(nonce.getBytes("UTF-8"));
+            StringBuilder sb = new StringBuilder();
+            for (byte b : digest) {
+                sb.append(String.format("%02x", b));
+            }
+            return sb.toString();
+        } catch (java.security.GeneralSecurityException e) {
+            throw new IOException("Failed to compute HMAC", e);
+        }
+    }
+
+    /**
+     * Generate a random nonce for a data channel
+     */
+    private static String newNonce() {
+        byte[] bytes = new byte[8];
+        new java.security.SecureRandom().nextBytes(bytes);
+        StringBuilder sb = new StringBuilder();
+        for (byte b : bytes) {
+            sb.append(String.format("%02x", b));
+        }
+        return sb.toString();
     }
 
     /**
@@ -224,15 +333,22 @@ public class RatholeClient {
         String type = jsonObject.get("type").getAsString();
 
         switch (type) {
+            case "Hello":
+                return new Hello(jsonObject.get("version").getAsInt(), new String[0]);
+            case "AuthChallenge":
+                return new AuthChallenge(jsonObject.get("nonce").getAsString());
+            case "TunnelRejected":
+                return new TunnelRejected(jsonObject.get("reason").getAsString());
             case "TunnelResponse":
                 int assignedPort = jsonObject.get("assigned_port").getAsInt();
-                return new TunnelResponse(assignedPort);
+                String session = jsonObject.get("session").getAsString();
+                return new TunnelResponse(assignedPort, session);
             case "CreateDataChannel":
-                return new CreateDataChannel();
+                return new CreateDataChannel(jsonObject.get("visitor_id").getAsLong());
             case "Heartbeat":
                 return new Heartbeat();
             default:
-                throw new IOException("Unknown message type: " + type);
+                return new Unknown(type);
         }
     }
 
@@ -267,6 +383,17 @@ public class RatholeClient {
         String type;
     }
 
+    static class Hello extends Message {
+        int version;
+        String[] capabilities;
+
+        Hello(int version, String[] capabilities) {
+            this.type = "Hello";
+            this.version = version;
+            this.capabilities = capabilities;
+        }
+    }
+
     static class TunnelRequest extends Message {
         int local_port;
 
@@ -276,13 +403,63 @@ public class RatholeClient {
         }
     }
 
+    static class AuthChallenge extends Message {
+        String nonce;
+
+        AuthChallenge(String nonce) {
+            this.type = "AuthChallenge";
+            this.nonce = nonce;
+        }
+    }
+
+    static class AuthResponse extends Message {
+        String digest;
+
+        AuthResponse(String digest) {
+            this.type = "AuthResponse";
+            this.digest = digest;
+        }
+    }
+
+    static class TunnelRejected extends Message {
+        String reason;
+
+        TunnelRejected(String reason) {
+            this.type = "TunnelRejected";
+            this.reason = reason;
+        }
+    }
+
     static class TunnelResponse extends Message {
         int assigned_port;
+        String session;
+
+        TunnelResponse(int assignedPort, String session) {
+            this.type = "TunnelResponse";
+            this.assigned_port = assignedPort;
+            this.session = session;
+        }
+    }
+
+    static class DataChannelHello extends Message {
+        String session;
+        String nonce;
+        long visitor_id;
+
+        DataChannelHello(String session, String nonce, long visitorId) {
+            this.type = "DataChannelHello";
+            this.session = session;
+            this.nonce = nonce;
+            this.visitor_id = visitorId;
+        }
     }
 
     static class CreateDataChannel extends Message {
-        CreateDataChannel() {
+        long visitor_id;
+
+        CreateDataChannel(long visitorId) {
             this.type = "CreateDataChannel";
+            this.visitor_id = visitorId;
         }
     }
 
@@ -292,12 +469,21 @@ public class RatholeClient {
         }
     }
 
+    /**
+     * A message type this client does not know (ignored)
+     */
+    static class Unknown extends Message {
+        Unknown(String type) {
+            this.type = type;
+        }
+    }
+
     /**
      * Main method for testing
      */
     public static void main(String[] args) {
-        if (args.length != 3) {
-            System.out.println("Usage: java RatholeClient <server_addr> <server_port> <local_port>");
+        if (args.length != 3 && args.length != 4) {
+            System.out.println("Usage: java RatholeClient <server_addr> <server_port> <local_port> [token]");
             System.out.println("Example: java RatholeClient localhost 2333 8080");
             System.exit(1);
         }
@@ -305,8 +491,9 @@ public class RatholeClient {
         String serverAddr = args[0];
         int serverPort = Integer.parseInt(args[1]);
         int localPort = Integer.parseInt(args[2]);
+        String token = args.length == 4 ? args[3] : null;
 
-        RatholeClient client = new RatholeClient(serverAddr, serverPort, localPort);
+        RatholeClient client = new RatholeClient(serverAddr, serverPort, localPort, token);
 
         try {
             client.start();
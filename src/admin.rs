@@ -0,0 +1,539 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+use crate::auth;
+use crate::config::ServiceProtocol;
+use crate::port_allocator::PortUsage;
+
+/// リクエストヘッダーの最大サイズ
+const MAX_REQUEST_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 管理 API の操作
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminCommand {
+    /// トンネルの一覧
+    ListTunnels,
+    /// トンネルを切断する（リモートポートで指定）
+    Disconnect(u16),
+    /// ポートの使用状況
+    ListPorts,
+    /// ポートを割り当てないよう押さえる
+    ReservePort(u16),
+    /// 押さえたポート・名前付きトンネル用の予約を解放する
+    ReleasePort(u16),
+}
+
+/// 操作の結果
+#[derive(Debug)]
+pub enum AdminReply {
+    Tunnels(Vec<TunnelSummary>),
+    Ports(PortUsage),
+    Done,
+    NotFound(String),
+    Conflict(String),
+}
+
+/// サーバーのタスクに渡す管理 API の要求
+pub struct AdminRequest {
+    pub command: AdminCommand,
+    pub reply: oneshot::Sender<AdminReply>,
+}
+
+/// 管理 API で見せるトンネルの情報
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TunnelSummary {
+    /// コントロールチャネルの接続元
+    pub client_addr: SocketAddr,
+    pub name: Option<String>,
+    /// 割り当てたリモートポート（トンネルの識別にも使う）
+    pub remote_port: u16,
+    /// クライアント側の転送先ポート
+    pub local_port: u16,
+    pub protocol: ServiceProtocol,
+    /// 接続した時刻（UNIX 時間の秒）
+    pub connected_since: u64,
+    /// 接続中の訪問者数
+    pub active_visitors: u64,
+    /// 訪問者からクライアントへ送ったバイト数
+    pub bytes_in: u64,
+    /// クライアントから訪問者へ送ったバイト数
+    pub bytes_out: u64,
+}
+
+/// 管理 API の待ち受け
+pub enum AdminListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl AdminListener {
+    /// `host:port` か `unix:/path` で待ち受ける
+    pub async fn bind(addr: &str) -> Result<Self> {
+        #[cfg(unix)]
+        if let Some(path) = addr.strip_prefix("unix:") {
+            use std::os::unix::fs::PermissionsExt;
+
+            // 前回のプロセスが残したソケットファイルは消してから待ち受ける
+            let _ = std::fs::remove_file(path);
+            let listener = UnixListener::bind(path)
+                .with_context(|| format!("Failed to bind admin API to {}", addr))?;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+                .with_context(|| format!("Failed to restrict permissions of {}", path))?;
+            return Ok(AdminListener::Unix(listener));
+        }
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to bind admin API to {}", addr))?;
+        Ok(AdminListener::Tcp(listener))
+    }
+}
+
+/// 管理 API のクライアント
+/// `bind_addr` と同じ形式（`host:port` か `unix:/path`）のアドレスに接続する
+#[derive(Debug, Clone)]
+pub struct AdminClient {
+    addr: String,
+    token: String,
+}
+
+impl AdminClient {
+    pub fn new(addr: impl Into<String>, token: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            token: token.into(),
+        }
+    }
+
+    /// トンネルの一覧（リモートポート順）
+    pub async fn tunnels(&self) -> Result<Vec<TunnelSummary>> {
+        #[derive(Deserialize)]
+        struct Tunnels {
+            tunnels: Vec<TunnelSummary>,
+        }
+        let body = self.request("GET", "/tunnels").await?;
+        let body: Tunnels = serde_json::from_value(body).context("Unexpected tunnel list")?;
+        Ok(body.tunnels)
+    }
+
+    /// ポートの使用状況
+    pub async fn ports(&self) -> Result<PortUsage> {
+        let body = self.request("GET", "/ports").await?;
+        serde_json::from_value(body).context("Unexpected port usage")
+    }
+
+    /// リモートポートを指定してトンネルを切断する
+    pub async fn disconnect(&self, port: u16) -> Result<()> {
+        self.request("DELETE", &format!("/tunnels/{}", port))
+            .await
+            .map(drop)
+    }
+
+    /// ポートを割り当てないよう押さえる
+    pub async fn reserve_port(&self, port: u16) -> Result<()> {
+        self.request("POST", &format!("/ports/{}/reserve", port))
+            .await
+            .map(drop)
+    }
+
+    /// 押さえたポート・名前付きトンネル用の予約を解放する
+    pub async fn release_port(&self, port: u16) -> Result<()> {
+        self.request("POST", &format!("/ports/{}/release", port))
+            .await
+            .map(drop)
+    }
+
+    async fn request(&self, method: &str, path: &str) -> Result<serde_json::Value> {
+        let response = async {
+            #[cfg(unix)]
+            if let Some(socket) = self.addr.strip_prefix("unix:") {
+                let stream = UnixStream::connect(socket)
+                    .await
+                    .with_context(|| format!("Failed to connect to admin API at {}", self.addr))?;
+                return exchange(stream, method, path, &self.token).await;
+            }
+            let stream = TcpStream::connect(&self.addr)
+                .await
+                .with_context(|| format!("Failed to connect to admin API at {}", self.addr))?;
+            exchange(stream, method, path, &self.token).await
+        };
+        timeout(REQUEST_TIMEOUT, response)
+            .await
+            .context("Timeout waiting for the admin API")?
+    }
+}
+
+/// リクエストを1つ送り、応答の JSON を返す（200 以外はエラー）
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    method: &str,
+    path: &str,
+    token: &str,
+) -> Result<serde_json::Value> {
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nConnection: close\r\n\r\n",
+        method, path, token
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+
+    let response = String::from_utf8(response).context("Response is not UTF-8")?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .context("Malformed response from admin API")?;
+    let status: u16 = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .context("Malformed status line from admin API")?;
+    let body: serde_json::Value =
+        serde_json::from_str(body).context("Malformed JSON from admin API")?;
+    if status != 200 {
+        let message = body["error"].as_str().unwrap_or("unknown error");
+        anyhow::bail!("Admin API returned {}: {}", status, message);
+    }
+    Ok(body)
+}
+
+/// 管理 API を提供する
+/// 1つの接続で1つのリクエストを受け付け、操作はサーバーのタスクに `requests` で渡す
+pub async fn serve(listener: AdminListener, token: String, requests: mpsc::Sender<AdminRequest>) {
+    let token = Arc::new(token);
+    loop {
+        match &listener {
+            AdminListener::Tcp(listener) => match listener.accept().await {
+                Ok((stream, addr)) => {
+                    debug!("Admin connection from {}", addr);
+                    spawn_handler(stream, token.clone(), requests.clone());
+                }
+                Err(e) => warn!("Failed to accept admin connection: {}", e),
+            },
+            #[cfg(unix)]
+            AdminListener::Unix(listener) => match listener.accept().await {
+                Ok((stream, _)) => {
+                    debug!("Admin connection on Unix domain socket");
+                    spawn_handler(stream, token.clone(), requests.clone());
+                }
+                Err(e) => warn!("Failed to accept admin connection: {}", e),
+            },
+        }
+    }
+}
+
+fn spawn_handler<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    stream: S,
+    token: Arc<String>,
+    requests: mpsc::Sender<AdminRequest>,
+) {
+    tokio::spawn(async move {
+        if let Err(e) = handle_connection(stream, &token, &requests).await {
+            debug!("Admin request failed: {:#}", e);
+        }
+    });
+}
+
+/// HTTP のリクエストを1つ処理して JSON で応答する
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    token: &str,
+    requests: &mpsc::Sender<AdminRequest>,
+) -> Result<()> {
+    let request = timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .context("Timeout reading admin request")
+        .and_then(|result| result);
+    let (status, body) = match request {
+        Ok(request) => respond(&request, token, requests).await,
+        Err(e) => (400, error_body(&format!("{:#}", e))),
+    };
+    write_response(&mut stream, status, &body).await
+}
+
+/// HTTP リクエストのうち管理 API が使う部分
+#[derive(Debug, PartialEq, Eq)]
+struct HttpRequest {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+}
+
+/// リクエスト行とヘッダーを読む（ボディは使わないので読まない）
+async fn read_request<R: AsyncRead + Unpin>(reader: &mut R) -> Result<HttpRequest> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("Connection closed before the end of the request header");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.len() > MAX_REQUEST_SIZE {
+            anyhow::bail!("Request header too large");
+        }
+    }
+
+    let head = std::str::from_utf8(&buf).context("Request header is not UTF-8")?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (Some(method), Some(path), Some(_version)) =
+        (request_line.next(), request_line.next(), request_line.next())
+    else {
+        anyhow::bail!("Malformed request line");
+    };
+    let authorization = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("authorization"))
+        .map(|(_, value)| value.trim().to_string());
+
+    Ok(HttpRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        authorization,
+    })
+}
+
+/// 認証して操作を実行し、ステータスコードと JSON を返す
+async fn respond(
+    request: &HttpRequest,
+    token: &str,
+    requests: &mpsc::Sender<AdminRequest>,
+) -> (u16, serde_json::Value) {
+    let authorized = request
+        .authorization
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map_or(false, |given| {
+            auth::constant_time_eq(given.trim().as_bytes(), token.as_bytes())
+        });
+    if !authorized {
+        return (401, error_body("invalid admin token"));
+    }
+
+    let command = match parse_command(&request.method, &request.path) {
+        Ok(command) => command,
+        Err((status, message)) => return (status, error_body(message)),
+    };
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let sent = requests
+        .send(AdminRequest {
+            command,
+            reply: reply_tx,
+        })
+        .await;
+    if sent.is_err() {
+        return (503, error_body("server is shutting down"));
+    }
+    match reply_rx.await {
+        Ok(AdminReply::Tunnels(tunnels)) => (200, json!({ "tunnels": tunnels })),
+        Ok(AdminReply::Ports(usage)) => (200, json!(usage)),
+        Ok(AdminReply::Done) => (200, json!({ "ok": true })),
+        Ok(AdminReply::NotFound(message)) => (404, error_body(&message)),
+        Ok(AdminReply::Conflict(message)) => (409, error_body(&message)),
+        Err(_) => (503, error_body("server is shutting down")),
+    }
+}
+
+/// メソッドとパスから操作を決める
+///
+/// | メソッド | パス                   | 操作 |
+/// |----------|------------------------|------|
+/// | GET      | /tunnels               | トンネルの一覧 |
+/// | DELETE   | /tunnels/{port}        | トンネルを切断 |
+/// | GET      | /ports                 | ポートの使用状況 |
+/// | POST     | /ports/{port}/reserve  | ポートを押さえる |
+/// | POST     | /ports/{port}/release  | ポートを解放する |
+fn parse_command(method: &str, path: &str) -> Result<AdminCommand, (u16, &'static str)> {
+    let path = path.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        ("GET", ["tunnels"]) => Ok(AdminCommand::ListTunnels),
+        ("DELETE", ["tunnels", port]) => Ok(AdminCommand::Disconnect(parse_port(port)?)),
+        ("GET", ["ports"]) => Ok(AdminCommand::ListPorts),
+        ("POST", ["ports", port, "reserve"]) => Ok(AdminCommand::ReservePort(parse_port(port)?)),
+        ("POST", ["ports", port, "release"]) => Ok(AdminCommand::ReleasePort(parse_port(port)?)),
+        (_, ["tunnels"] | ["tunnels", _] | ["ports"] | ["ports", _, "reserve" | "release"]) => {
+            Err((405, "method not allowed"))
+        }
+        _ => Err((404, "not found")),
+    }
+}
+
+fn parse_port(port: &str) -> Result<u16, (u16, &'static str)> {
+    port.parse().map_err(|_| (400, "invalid port"))
+}
+
+fn error_body(message: &str) -> serde_json::Value {
+    json!({ "error": message })
+}
+
+/// JSON を返して接続を閉じる
+async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    status: u16,
+    body: &serde_json::Value,
+) -> Result<()> {
+    let body = body.to_string();
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        _ => "Service Unavailable",
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        body.len()
+    );
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(body.as_bytes()).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let mut raw: &[u8] =
+            b"DELETE /tunnels/35100 HTTP/1.1\r\nHost: localhost\r\nauthorization: Bearer s3cret\r\n\r\n";
+        let request = read_request(&mut raw).await.unwrap();
+        assert_eq!(
+            request,
+            HttpRequest {
+                method: "DELETE".to_string(),
+                path: "/tunnels/35100".to_string(),
+                authorization: Some("Bearer s3cret".to_string()),
+            }
+        );
+
+        // ヘッダーが終わらないうちに切れたらエラー
+        let mut raw: &[u8] = b"GET /tunnels HTTP/1.1\r\n";
+        assert!(read_request(&mut raw).await.is_err());
+
+        // 上限を超えるヘッダーはエラー
+        let mut raw = b"GET /tunnels HTTP/1.1\r\nX-Padding: ".to_vec();
+        raw.resize(MAX_REQUEST_SIZE + 1, b'a');
+        raw.extend_from_slice(b"\r\n\r\n");
+        assert!(read_request(&mut raw.as_slice()).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command("GET", "/tunnels"), Ok(AdminCommand::ListTunnels));
+        assert_eq!(
+            parse_command("DELETE", "/tunnels/35100"),
+            Ok(AdminCommand::Disconnect(35100))
+        );
+        assert_eq!(parse_command("GET", "/ports/"), Ok(AdminCommand::ListPorts));
+        assert_eq!(
+            parse_command("POST", "/ports/35100/reserve"),
+            Ok(AdminCommand::ReservePort(35100))
+        );
+        assert_eq!(
+            parse_command("POST", "/ports/35100/release"),
+            Ok(AdminCommand::ReleasePort(35100))
+        );
+        assert_eq!(parse_command("DELETE", "/tunnels/web").unwrap_err().0, 400);
+        assert_eq!(parse_command("POST", "/tunnels").unwrap_err().0, 405);
+        assert_eq!(parse_command("GET", "/metrics").unwrap_err().0, 404);
+    }
+
+    #[tokio::test]
+    async fn test_client() {
+        let listener = AdminListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = match &listener {
+            AdminListener::Tcp(listener) => listener.local_addr().unwrap(),
+            #[cfg(unix)]
+            AdminListener::Unix(_) => unreachable!(),
+        };
+        let (requests, mut requests_rx) = mpsc::channel(1);
+        tokio::spawn(serve(listener, "s3cret".to_string(), requests));
+        tokio::spawn(async move {
+            while let Some(request) = requests_rx.recv().await {
+                let reply = match request.command {
+                    AdminCommand::ListTunnels => AdminReply::Tunnels(vec![TunnelSummary {
+                        client_addr: "192.0.2.1:50000".parse().unwrap(),
+                        name: Some("web".to_string()),
+                        remote_port: 35100,
+                        local_port: 8080,
+                        protocol: ServiceProtocol::Tcp,
+                        connected_since: 1_700_000_000,
+                        active_visitors: 2,
+                        bytes_in: 100,
+                        bytes_out: 2000,
+                    }]),
+                    _ => AdminReply::NotFound("no tunnel on port 35101".to_string()),
+                };
+                let _ = request.reply.send(reply);
+            }
+        });
+
+        let client = AdminClient::new(addr.to_string(), "s3cret");
+        let tunnels = client.tunnels().await.unwrap();
+        assert_eq!(tunnels.len(), 1);
+        assert_eq!(tunnels[0].name.as_deref(), Some("web"));
+        assert_eq!(tunnels[0].bytes_out, 2000);
+
+        // エラーはサーバーのメッセージ付きで返る
+        let err = client.disconnect(35101).await.unwrap_err();
+        assert!(err.to_string().contains("no tunnel on port 35101"));
+        let err = AdminClient::new(addr.to_string(), "wrong")
+            .tunnels()
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("401"));
+    }
+
+    #[tokio::test]
+    async fn test_respond() {
+        let (requests, mut requests_rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            while let Some(request) = requests_rx.recv().await {
+                let reply = match request.command {
+                    AdminCommand::Disconnect(35100) => AdminReply::Done,
+                    _ => AdminReply::NotFound("no tunnel".to_string()),
+                };
+                let _ = request.reply.send(reply);
+            }
+        });
+        let request = |authorization: Option<&str>, path: &str| HttpRequest {
+            method: "DELETE".to_string(),
+            path: path.to_string(),
+            authorization: authorization.map(str::to_string),
+        };
+
+        // トークンがない・違えば操作しない
+        let (status, _) = respond(&request(None, "/tunnels/35100"), "s3cret", &requests).await;
+        assert_eq!(status, 401);
+        let (status, _) =
+            respond(&request(Some("Bearer wrong"), "/tunnels/35100"), "s3cret", &requests).await;
+        assert_eq!(status, 401);
+
+        let (status, body) =
+            respond(&request(Some("Bearer s3cret"), "/tunnels/35100"), "s3cret", &requests).await;
+        assert_eq!(status, 200);
+        assert_eq!(body, json!({ "ok": true }));
+        let (status, _) =
+            respond(&request(Some("Bearer s3cret"), "/tunnels/35101"), "s3cret", &requests).await;
+        assert_eq!(status, 404);
+    }
+}
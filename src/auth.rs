@@ -0,0 +1,145 @@
+use sha2::{Digest, Sha256};
+use std::collections::{HashSet, VecDeque};
+
+const BLOCK_SIZE: usize = 64;
+
+/// チャレンジ用のランダムなnonceを生成
+pub fn new_nonce() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+/// チャレンジに対するレスポンス（HMAC-SHA256(token, nonce) の16進文字列）を計算
+pub fn compute_digest(token: &str, nonce: &str) -> String {
+    hex::encode(hmac_sha256(token.as_bytes(), nonce.as_bytes()))
+}
+
+/// レスポンスを検証し、一致したトークンを返す
+pub fn verify_digest<'a>(
+    tokens: impl IntoIterator<Item = &'a str>,
+    nonce: &str,
+    digest: &str,
+) -> Option<&'a str> {
+    tokens
+        .into_iter()
+        .find(|token| constant_time_eq(compute_digest(token, nonce).as_bytes(), digest.as_bytes()))
+}
+
+/// 最近受け取ったnonceを覚えておき、使い回しを見つける
+/// 覚えておく数には上限があり、古いものから忘れる
+pub struct NonceHistory {
+    capacity: usize,
+    seen: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl NonceHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// 初めて見るnonceなら記録して true、覚えているnonceなら false
+    pub fn insert(&mut self, nonce: &str) -> bool {
+        if self.seen.contains(nonce) {
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(nonce.to_string());
+        self.order.push_back(nonce.to_string());
+        true
+    }
+}
+
+/// HMAC-SHA256 (RFC 2104)
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    // ブロック長より長い鍵はハッシュして使う
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut ipad = [0x36u8; BLOCK_SIZE];
+    let mut opad = [0x5cu8; BLOCK_SIZE];
+    for i in 0..BLOCK_SIZE {
+        ipad[i] ^= block[i];
+        opad[i] ^= block[i];
+    }
+
+    let inner = Sha256::new().chain_update(ipad).chain_update(message).finalize();
+    Sha256::new()
+        .chain_update(opad)
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+/// 比較にかかる時間が内容に依存しないバイト列比較
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hmac_sha256_rfc4231() {
+        // RFC 4231 Test Case 2
+        let digest = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(
+            hex::encode(digest),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+
+        // RFC 4231 Test Case 6（ブロック長より長い鍵）
+        let digest = hmac_sha256(
+            &[0xaa; 131],
+            b"Test Using Larger Than Block-Size Key - Hash Key First",
+        );
+        assert_eq!(
+            hex::encode(digest),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn test_verify_digest() {
+        let tokens = ["alpha", "beta"];
+        let nonce = new_nonce();
+
+        let digest = compute_digest("beta", &nonce);
+        assert_eq!(verify_digest(tokens, &nonce, &digest), Some("beta"));
+
+        // 不明なトークン・別のnonceに対するレスポンスは拒否
+        let digest = compute_digest("gamma", &nonce);
+        assert_eq!(verify_digest(tokens, &nonce, &digest), None);
+        let digest = compute_digest("alpha", &new_nonce());
+        assert_eq!(verify_digest(tokens, &nonce, &digest), None);
+    }
+
+    #[test]
+    fn test_nonce_history() {
+        let mut history = NonceHistory::new(2);
+        assert!(history.insert("a"));
+        assert!(history.insert("b"));
+        // 使い回しは拒否
+        assert!(!history.insert("a"));
+
+        // 上限を超えると古いものから忘れる
+        assert!(history.insert("c"));
+        assert!(history.insert("a"));
+        assert!(!history.insert("c"));
+    }
+}
@@ -1,170 +1,629 @@
 use anyhow::{Context, Result};
+use backoff::backoff::Backoff;
+use backoff::{ExponentialBackoff, ExponentialBackoffBuilder};
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::net::TcpStream;
-use tokio::sync::broadcast;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::net::{TcpStream, UdpSocket};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::sync::{broadcast, mpsc, watch};
 use tokio::time::timeout;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
-use crate::protocol::Message;
+use crate::auth;
+use crate::config::{
+    ClientConfig, RetryConfig, ServiceConfig, ServiceProtocol, TimeoutConfig, TransportType,
+};
+use crate::error::{Error, TunnelError};
+use crate::mux;
+use crate::protocol::{
+    self, ErrorCode, Message, MessageReader, POOLED_VISITOR_ID, PROTOCOL_VERSION,
+};
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use crate::transport::TlsTransport;
+#[cfg(feature = "noise")]
+use crate::transport::NoiseTransport;
+#[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
+use crate::transport::WebsocketTransport;
+use crate::transport::{TcpTransport, Transport};
+use crate::tunnel::{TunnelEvent, TunnelEventKind};
+use crate::udp;
 
-const RETRY_INTERVAL: Duration = Duration::from_secs(3);
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
-const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(60);
+/// サービスごとの接続状態
+#[derive(Debug, Clone, Default)]
+pub struct ServiceStatus {
+    /// 最後に割り当てられたリモートポート（まだ割り当てられていなければ 0）
+    pub remote_port: u16,
+    /// コントロールチャネルが接続中かどうか
+    pub connected: bool,
+    /// 最後の接続エラー（接続に成功すると消える）
+    pub last_error: Option<String>,
+    /// 最後の接続エラーの分類（接続に成功すると消える）
+    pub error: Option<Error>,
+    /// 再接続をあきらめたかどうか（再試行できないエラー・`max_elapsed_secs` の超過）
+    pub gave_up: bool,
+}
 
-/// サーバーに接続してポート番号を取得
-pub async fn connect_and_get_port(
-    remote_addr: String,
-    local_port: u16,
-    mut shutdown_rx: broadcast::Receiver<()>,
-) -> Result<u16> {
-    loop {
-        tokio::select! {
-            result = try_connect(&remote_addr, local_port) => {
-                match result {
-                    Ok(port) => return Ok(port),
-                    Err(e) => {
-                        warn!("Connection failed: {}, retrying in {:?}...", e, RETRY_INTERVAL);
-                        tokio::time::sleep(RETRY_INTERVAL).await;
-                    }
-                }
-            }
-            _ = shutdown_rx.recv() => {
-                return Err(anyhow::anyhow!("Shutdown requested"));
-            }
-        }
+/// サービスで起きたことを `Tunnel::events` に通知する
+#[derive(Debug, Clone)]
+struct ServiceEvents {
+    /// `services` の中での位置
+    service: usize,
+    tx: broadcast::Sender<TunnelEvent>,
+}
+
+impl ServiceEvents {
+    fn send(&self, kind: TunnelEventKind) {
+        // 購読者がいなければ捨てる
+        let _ = self.tx.send(TunnelEvent {
+            service: self.service,
+            kind,
+        });
     }
 }
 
 /// クライアントを実行（メインループ）
+/// サービスごとにコントロールチャネルを張り、それぞれ独立して再接続する
+/// 接続状態は `services` と同じ順に並んだ `status_txs` で通知する。再接続時は前回のポートを希望する
 pub async fn run_client(
-    remote_addr: String,
-    local_port: u16,
-    mut shutdown_rx: broadcast::Receiver<()>,
+    config: ClientConfig,
+    status_txs: Vec<watch::Sender<ServiceStatus>>,
+    events: broadcast::Sender<TunnelEvent>,
+    shutdown_rx: broadcast::Receiver<()>,
+) -> Result<()> {
+    // ws:// / wss:// の URL が指定されたら WebSocket で接続する
+    let mut transport_type = config.transport.transport_type;
+    let remote_addr = config.remote_addr.as_str();
+    if transport_type == TransportType::Tcp
+        && (remote_addr.starts_with("ws://") || remote_addr.starts_with("wss://"))
+    {
+        transport_type = TransportType::Websocket;
+    }
+
+    match transport_type {
+        TransportType::Tcp => {
+            run_client_with_transport::<TcpTransport>(config, status_txs, events, shutdown_rx)
+                .await
+        }
+        TransportType::Tls => {
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            let result =
+                run_client_with_transport::<TlsTransport>(config, status_txs, events, shutdown_rx)
+                    .await;
+            #[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+            let result = crate::transport::feature_not_compiled("tls");
+            result
+        }
+        TransportType::Noise => {
+            #[cfg(feature = "noise")]
+            let result =
+                run_client_with_transport::<NoiseTransport>(
+                    config,
+                    status_txs,
+                    events,
+                    shutdown_rx,
+                )
+                .await;
+            #[cfg(not(feature = "noise"))]
+            let result = crate::transport::feature_not_compiled("noise");
+            result
+        }
+        TransportType::Websocket => {
+            #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
+            let result = run_client_with_transport::<WebsocketTransport>(
+                config,
+                status_txs,
+                events,
+                shutdown_rx,
+            )
+            .await;
+            #[cfg(not(any(feature = "websocket-native-tls", feature = "websocket-rustls")))]
+            let result = crate::transport::feature_not_compiled("websocket");
+            result
+        }
+    }
+}
+
+/// 指定したトランスポートでクライアントを実行
+/// すべてのサービスが終了するまで待つ
+async fn run_client_with_transport<T: Transport>(
+    config: ClientConfig,
+    status_txs: Vec<watch::Sender<ServiceStatus>>,
+    events: broadcast::Sender<TunnelEvent>,
+    shutdown_rx: broadcast::Receiver<()>,
 ) -> Result<()> {
+    let transport = Arc::new(T::new(&config.transport).map_err(Error::config)?);
+    let config = Arc::new(config);
+
+    let handles: Vec<_> = config
+        .services
+        .iter()
+        .cloned()
+        .map(Arc::new)
+        .zip(status_txs)
+        .enumerate()
+        .map(|(index, (service, status_tx))| {
+            let span = info_span!("service", name = %service.display_name());
+            let events = ServiceEvents {
+                service: index,
+                tx: events.clone(),
+            };
+            tokio::spawn(
+                run_service(
+                    config.clone(),
+                    service,
+                    transport.clone(),
+                    status_tx,
+                    events,
+                    shutdown_rx.resubscribe(),
+                )
+                .instrument(span),
+            )
+        })
+        .collect();
+
+    for handle in handles {
+        handle.await?;
+    }
+    Ok(())
+}
+
+/// 1つのサービスのトンネルを維持する（切断されたら再接続）
+async fn run_service<T: Transport>(
+    config: Arc<ClientConfig>,
+    service: Arc<ServiceConfig>,
+    transport: Arc<T>,
+    status_tx: watch::Sender<ServiceStatus>,
+    events: ServiceEvents,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) {
+    let mut backoff = new_backoff(&config.retry);
+    // 最後に接続できてから何回目の再接続か
+    let mut attempt: u32 = 0;
     loop {
-        tokio::select! {
-            result = try_run_client(&remote_addr, local_port) => {
-                match result {
-                    Ok(_) => {
-                        info!("Client disconnected normally");
-                        return Ok(());
+        let result = tokio::select! {
+            result = try_run_client(&config, &service, &transport, &status_tx, &events) => result,
+            _ = shutdown_rx.recv() => {
+                info!("Client shutdown requested");
+                return;
+            }
+        };
+        // 一度つながったら間隔と経過時間を最初から数え直す
+        let was_connected = status_tx.borrow().connected;
+        if was_connected {
+            backoff.reset();
+            attempt = 0;
+        }
+        status_tx.send_modify(|status| status.connected = false);
+
+        match result {
+            Ok(_) => {
+                info!("Client disconnected normally");
+                if was_connected {
+                    events.send(TunnelEventKind::Disconnected { error: None });
+                }
+                return;
+            }
+            Err(e) => {
+                let message = format!("{:#}", e);
+                let error = Error::from(e);
+                if was_connected {
+                    events.send(TunnelEventKind::Disconnected {
+                        error: Some(error.clone()),
+                    });
+                }
+                // 認証の失敗・バージョンの不一致など、再試行しても成功しないエラーでは止まる
+                let fatal = error.rejection().map_or(false, |rejection| !rejection.retryable);
+                let delay = if fatal { None } else { backoff.next_backoff() };
+                status_tx.send_modify(|status| {
+                    status.last_error = Some(message.clone());
+                    status.error = Some(error.clone());
+                    status.gave_up = delay.is_none();
+                });
+                let Some(delay) = delay else {
+                    if fatal {
+                        error!("Client error: {}, not retrying", message);
+                    } else {
+                        error!(
+                            "Client error: {}, giving up after {:?} without a connection",
+                            message,
+                            backoff.get_elapsed_time()
+                        );
                     }
-                    Err(e) => {
-                        error!("Client error: {}, retrying in {:?}...", e, RETRY_INTERVAL);
-                        tokio::time::sleep(RETRY_INTERVAL).await;
+                    events.send(TunnelEventKind::GaveUp { error });
+                    return;
+                };
+                attempt += 1;
+                error!("Client error: {}, retrying in {:?}...", message, delay);
+                events.send(TunnelEventKind::Reconnecting { attempt, delay });
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = shutdown_rx.recv() => {
+                        info!("Client shutdown requested");
+                        return;
                     }
                 }
             }
-            _ = shutdown_rx.recv() => {
-                info!("Client shutdown requested");
-                return Ok(());
-            }
         }
     }
 }
 
-/// サーバーに接続を試行
-async fn try_connect(remote_addr: &str, local_port: u16) -> Result<u16> {
-    debug!("Connecting to server: {}", remote_addr);
+/// 設定から再接続の間隔を作る
+fn new_backoff(retry: &RetryConfig) -> ExponentialBackoff {
+    ExponentialBackoffBuilder::new()
+        .with_initial_interval(Duration::from_millis(retry.initial_interval_ms))
+        .with_max_interval(Duration::from_millis(retry.max_interval_ms))
+        .with_multiplier(retry.multiplier)
+        .with_randomization_factor(retry.jitter)
+        .with_max_elapsed_time(retry.max_elapsed_secs.map(Duration::from_secs))
+        .build()
+}
 
-    let mut stream = TcpStream::connect(remote_addr)
-        .await
-        .with_context(|| format!("Failed to connect to {}", remote_addr))?;
+/// クライアント実行を試行
+async fn try_run_client<T: Transport>(
+    config: &ClientConfig,
+    service: &Arc<ServiceConfig>,
+    transport: &Arc<T>,
+    status_tx: &watch::Sender<ServiceStatus>,
+    events: &ServiceEvents,
+) -> Result<()> {
+    let remote_addr = config.remote_addr.as_str();
+    let local_port = service.local_port;
+    let connect_timeout = Duration::from_secs(config.timeouts.connect_secs);
+    let handshake_timeout = Duration::from_secs(config.timeouts.handshake_secs);
+    debug!("Starting client for {} -> {}", remote_addr, service.local_addr());
 
-    // トンネル作成をリクエスト
-    Message::TunnelRequest { local_port }
-        .write_to(&mut stream)
+    let mut stream = connect(transport.as_ref(), remote_addr, connect_timeout)
         .await
-        .context("Failed to send TunnelRequest")?;
+        .map_err(Error::connection)?;
+    if !hello(&mut stream, handshake_timeout).await? {
+        // Hello を知らない古いサーバーは接続を閉じるので、つなぎ直して Hello なしで続ける
+        info!(
+            "Server does not support the Hello handshake, assuming protocol version {}",
+            PROTOCOL_VERSION
+        );
+        stream = connect(transport.as_ref(), remote_addr, connect_timeout)
+            .await
+            .map_err(Error::connection)?;
+    }
 
-    // 割り当てられたポートを受信
-    let assigned_port = timeout(Duration::from_secs(10), Message::read_from(&mut stream))
-        .await
-        .context("Timeout waiting for TunnelResponse")??;
+    // 指定されたポート、なければ再接続時は前回と同じポートを希望する
+    let previous_port = status_tx.borrow().remote_port;
+    let remote_port = service
+        .remote_port
+        .or_else(|| (previous_port != 0).then_some(previous_port));
 
-    match assigned_port {
-        Message::TunnelResponse { assigned_port } => {
-            info!("Tunnel established! Remote port: {}", assigned_port);
-            Ok(assigned_port)
-        }
-        _ => Err(anyhow::anyhow!("Unexpected response from server")),
+    // トンネル作成をリクエスト
+    Message::TunnelRequest {
+        local_port,
+        remote_port,
+        name: service.name.clone(),
+        protocol: service.protocol,
+        multiplex: config.multiplex,
+        pool_size: config.pool_size,
     }
-}
-
-/// クライアント実行を試行
-async fn try_run_client(remote_addr: &str, local_port: u16) -> Result<()> {
-    debug!("Starting client for {}:{}", remote_addr, local_port);
+    .write_to(&mut stream)
+    .await
+    .context("Failed to send TunnelRequest")?;
 
-    let mut stream = TcpStream::connect(remote_addr)
+    // 割り当てられたポートを受信（認証が有効ならその前にチャレンジが来る）
+    let mut response = timeout(handshake_timeout, Message::read_from(&mut stream))
         .await
-        .with_context(|| format!("Failed to connect to {}", remote_addr))?;
+        .context("Timeout waiting for TunnelResponse")??;
 
-    // トンネル作成をリクエスト
-    Message::TunnelRequest { local_port }
+    if let Message::AuthChallenge { nonce } = response {
+        let Some(token) = config.token.as_deref() else {
+            return Err(TunnelError {
+                code: ErrorCode::AuthFailed,
+                message: "server requires authentication but no token is configured".to_string(),
+                retryable: false,
+            }
+            .into());
+        };
+        Message::AuthResponse {
+            digest: auth::compute_digest(token, &nonce),
+        }
         .write_to(&mut stream)
         .await
-        .context("Failed to send TunnelRequest")?;
+        .context("Failed to send AuthResponse")?;
 
-    // 割り当てられたポートを受信
-    let response = timeout(Duration::from_secs(10), Message::read_from(&mut stream))
-        .await
-        .context("Timeout waiting for TunnelResponse")??;
+        response = timeout(handshake_timeout, Message::read_from(&mut stream))
+            .await
+            .context("Timeout waiting for TunnelResponse")??;
+    }
 
-    let assigned_port = match response {
-        Message::TunnelResponse { assigned_port } => assigned_port,
+    let (assigned_port, session, multiplex, pool_size) = match response {
+        Message::TunnelResponse {
+            assigned_port,
+            session,
+            multiplex,
+            pool_size,
+        } => (assigned_port, session, multiplex, pool_size),
+        Message::TunnelRejected { reason } => {
+            // 古いサーバーは理由の種類を返さないので、再試行できるものとして扱う
+            return Err(TunnelError {
+                code: ErrorCode::Other,
+                message: reason,
+                retryable: true,
+            }
+            .into())
+        }
+        Message::Error {
+            code,
+            message,
+            retryable,
+        } => {
+            return Err(TunnelError {
+                code,
+                message,
+                retryable,
+            }
+            .into())
+        }
         _ => return Err(anyhow::anyhow!("Unexpected response from server")),
     };
 
+    if previous_port != 0 && previous_port != assigned_port {
+        warn!(
+            "Remote port changed from {} to {} after reconnect",
+            previous_port, assigned_port
+        );
+        events.send(TunnelEventKind::PortChanged {
+            old: previous_port,
+            new: assigned_port,
+        });
+    } else if let Some(port) = remote_port.filter(|port| *port != assigned_port) {
+        warn!(
+            "Requested remote port {} is not available, got {}",
+            port, assigned_port
+        );
+    }
     info!("Connected! Remote port: {}", assigned_port);
 
+    // 多重化に対応していない古いサーバーには訪問者ごとにデータチャネルを張る
+    let mux = if multiplex {
+        let mut conn = connect(transport.as_ref(), remote_addr, connect_timeout)
+            .await
+            .context("Failed to open multiplexed connection")?;
+        Message::MuxChannelHello {
+            session: session.clone(),
+        }
+        .write_to(&mut conn)
+        .await
+        .context("Failed to send MuxChannelHello")?;
+        debug!("Multiplexed connection established");
+        Some(mux::Session::new(conn, mux::Side::Client))
+    } else {
+        if config.multiplex {
+            info!("Server does not support multiplexing, using a connection per visitor");
+        }
+        None
+    };
+
+    // 待機中のデータチャネルを張っておき、訪問者が来たらすぐに使ってもらう
+    // 使われた分はサーバーから補充を頼まれる
+    if pool_size > 0 {
+        debug!("Opening {} idle data channels", pool_size);
+        for _ in 0..pool_size {
+            let transport = transport.clone();
+            let remote_addr = remote_addr.to_string();
+            let session = session.clone();
+            let service = service.clone();
+            let events = events.clone();
+            tokio::spawn(
+                async move {
+                    if let Err(e) = create_data_channel(
+                        transport,
+                        remote_addr,
+                        session,
+                        POOLED_VISITOR_ID,
+                        service,
+                        connect_timeout,
+                        events,
+                    )
+                    .await
+                    {
+                        error!("Data channel error: {}", e);
+                    }
+                }
+                .in_current_span(),
+            );
+        }
+    } else if config.pool_size > 0 && !multiplex {
+        info!("Server does not support idle data channels, opening them on demand");
+    }
+
+    status_tx.send_replace(ServiceStatus {
+        remote_port: assigned_port,
+        connected: true,
+        last_error: None,
+        error: None,
+        gave_up: false,
+    });
+    events.send(TunnelEventKind::Connected {
+        port: assigned_port,
+    });
+
     // コントロールチャネルループ
-    control_channel_loop(stream, remote_addr.to_string(), local_port).await
+    control_channel_loop(
+        stream,
+        transport.clone(),
+        remote_addr.to_string(),
+        session,
+        service.clone(),
+        mux,
+        config.timeouts,
+        events.clone(),
+    )
+    .await
+}
+
+/// サーバーに接続する。`connect_timeout` を過ぎたら失敗とする
+async fn connect<T: Transport>(
+    transport: &T,
+    remote_addr: &str,
+    connect_timeout: Duration,
+) -> Result<T::Stream> {
+    timeout(connect_timeout, transport.connect(remote_addr))
+        .await
+        .with_context(|| format!("Timeout connecting to {}", remote_addr))?
+}
+
+/// プロトコルのバージョンと対応機能をサーバーと伝え合う
+/// Hello を知らない古いサーバーが接続を閉じた場合は false を返す
+async fn hello<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    handshake_timeout: Duration,
+) -> Result<bool> {
+    Message::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: protocol::capabilities(),
+    }
+    .write_to(stream)
+    .await
+    .context("Failed to send Hello")?;
+
+    let response = timeout(handshake_timeout, Message::read_from(stream))
+        .await
+        .context("Timeout waiting for Hello")?;
+
+    match response {
+        Ok(Message::Hello {
+            version,
+            capabilities,
+        }) => {
+            if version != PROTOCOL_VERSION {
+                // 再接続しても話せるようにはならないのであきらめる
+                return Err(TunnelError {
+                    code: ErrorCode::UnsupportedVersion,
+                    message: format!(
+                        "server speaks protocol version {}, this client speaks {}",
+                        version, PROTOCOL_VERSION
+                    ),
+                    retryable: false,
+                }
+                .into());
+            }
+            debug!(
+                "Server speaks protocol version {} (capabilities: {})",
+                version,
+                capabilities.join(", ")
+            );
+            Ok(true)
+        }
+        Ok(Message::TunnelRejected { reason }) => Err(TunnelError {
+            code: ErrorCode::Other,
+            message: reason,
+            retryable: true,
+        }
+        .into()),
+        Ok(Message::Error {
+            code,
+            message,
+            retryable,
+        }) => Err(TunnelError {
+            code,
+            message,
+            retryable,
+        }
+        .into()),
+        Ok(msg) => Err(anyhow::anyhow!("Unexpected response to Hello: {:?}", msg)),
+        Err(e) => {
+            debug!("No Hello from server: {:#}", e);
+            Ok(false)
+        }
+    }
 }
 
 /// コントロールチャネルのメインループ
-async fn control_channel_loop(
-    mut stream: TcpStream,
+#[allow(clippy::too_many_arguments)]
+async fn control_channel_loop<T: Transport>(
+    stream: T::Stream,
+    transport: Arc<T>,
     remote_addr: String,
-    local_port: u16,
+    session: String,
+    service: Arc<ServiceConfig>,
+    mut mux: Option<mux::Session>,
+    timeouts: TimeoutConfig,
+    events: ServiceEvents,
 ) -> Result<()> {
-    let mut heartbeat_interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let connect_timeout = Duration::from_secs(timeouts.connect_secs);
+    let heartbeat_timeout = Duration::from_secs(timeouts.heartbeat_timeout_secs);
+    let (read_half, mut stream) = tokio::io::split(stream);
+    let mut reader = MessageReader::spawn(read_half);
+    let mut heartbeat_interval =
+        tokio::time::interval(Duration::from_secs(timeouts.heartbeat_interval_secs));
+    let heartbeat_deadline = tokio::time::sleep(heartbeat_timeout);
+    tokio::pin!(heartbeat_deadline);
 
     loop {
         tokio::select! {
             // サーバーからのメッセージを受信
-            msg_result = timeout(HEARTBEAT_TIMEOUT, Message::read_from(&mut stream)) => {
-                match msg_result {
-                    Ok(Ok(msg)) => {
-                        match msg {
-                            Message::CreateDataChannel => {
-                                debug!("Received CreateDataChannel request");
-                                // データチャネルを非同期で作成
-                                let remote_addr_clone = remote_addr.clone();
-                                tokio::spawn(async move {
-                                    if let Err(e) = create_data_channel(remote_addr_clone, local_port).await {
-                                        error!("Data channel error: {}", e);
-                                    }
-                                });
-                            }
-                            Message::Heartbeat => {
-                                debug!("Received heartbeat");
-                                // ハートビートを返す
-                                Message::Heartbeat.write_to(&mut stream).await?;
+            msg_result = reader.recv() => {
+                let msg = msg_result.context("Failed to read message")?;
+                // 何か受信できればコネクションは生きている
+                heartbeat_deadline
+                    .as_mut()
+                    .reset(tokio::time::Instant::now() + heartbeat_timeout);
+                match msg {
+                    Message::CreateDataChannel { visitor_id } => {
+                        debug!("Received CreateDataChannel request for visitor {}", visitor_id);
+                        // データチャネルを非同期で作成
+                        let transport = transport.clone();
+                        let remote_addr_clone = remote_addr.clone();
+                        let session_clone = session.clone();
+                        let service_clone = service.clone();
+                        let events = events.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = create_data_channel(transport, remote_addr_clone, session_clone, visitor_id, service_clone, connect_timeout, events).await {
+                                error!("Data channel error: {}", e);
                             }
-                            _ => {
-                                warn!("Unexpected message: {:?}", msg);
-                            }
-                        }
+                        }.in_current_span());
+                    }
+                    Message::Heartbeat => {
+                        // 双方が定期的に送信するので返信はしない
+                        debug!("Received heartbeat");
+                    }
+                    Message::TunnelRejected { reason } => {
+                        // 同じ名前の新しいトンネルに置き換えられた場合など。再接続はしない
+                        warn!("Tunnel closed by server: {}", reason);
+                        return Ok(());
                     }
-                    Ok(Err(e)) => {
-                        return Err(e).context("Failed to read message");
+                    Message::Error { code, message, retryable } => {
+                        // 再接続するかどうかは retryable に従う
+                        return Err(TunnelError { code, message, retryable }.into());
                     }
-                    Err(_) => {
-                        return Err(anyhow::anyhow!("Heartbeat timeout"));
+                    Message::Unknown => {
+                        // 新しいサーバーの知らないメッセージは無視する
+                        debug!("Ignoring unknown message");
+                    }
+                    _ => {
+                        warn!("Unexpected message: {:?}", msg);
                     }
                 }
             }
 
+            // 多重化した接続の上でサーバーが開いたストリーム（訪問者ごと）
+            data_stream = accept_mux(&mut mux) => {
+                let Some(data_stream) = data_stream else {
+                    return Err(anyhow::anyhow!("Multiplexed connection closed"));
+                };
+                debug!("Received multiplexed stream");
+                let service_clone = service.clone();
+                let events = events.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve_local(data_stream, &service_clone, &events).await {
+                        error!("Data channel error: {}", e);
+                    }
+                }.in_current_span());
+            }
+
+            // 一定時間何も受信しなければ切断とみなす
+            _ = &mut heartbeat_deadline => {
+                return Err(anyhow::anyhow!("Heartbeat timeout"));
+            }
+
             // 定期的にハートビートを送信
             _ = heartbeat_interval.tick() => {
                 debug!("Sending heartbeat");
@@ -177,22 +636,163 @@ async fn control_channel_loop(
 }
 
 /// データチャネルを作成
-async fn create_data_channel(remote_addr: String, local_port: u16) -> Result<()> {
+async fn create_data_channel<T: Transport>(
+    transport: Arc<T>,
+    remote_addr: String,
+    session: String,
+    visitor_id: u64,
+    service: Arc<ServiceConfig>,
+    connect_timeout: Duration,
+    events: ServiceEvents,
+) -> Result<()> {
     debug!("Creating data channel to {}", remote_addr);
 
     // サーバーに接続
-    let server_stream = TcpStream::connect(&remote_addr)
+    let mut server_stream = connect(transport.as_ref(), &remote_addr, connect_timeout)
         .await
         .with_context(|| format!("Failed to connect to server at {}", remote_addr))?;
 
-    // ローカルサービスに接続
-    let local_stream = TcpStream::connect(format!("127.0.0.1:{}", local_port))
+    // セッショントークンでコントロールチャネルと、訪問者IDで訪問者と紐付ける
+    // 送信元アドレスはNAT越しでは変わるため照合には使えない
+    Message::DataChannelHello {
+        session,
+        nonce: hex::encode(rand::random::<[u8; 8]>()),
+        visitor_id,
+    }
+    .write_to(&mut server_stream)
+    .await
+    .context("Failed to send DataChannelHello")?;
+
+    // 待機中のデータチャネルは訪問者が割り当てられるまでローカルサービスに接続しない
+    if visitor_id == POOLED_VISITOR_ID {
+        match Message::read_from(&mut server_stream).await {
+            Ok(Message::CreateDataChannel { visitor_id }) => {
+                debug!("Idle data channel assigned to visitor {}", visitor_id);
+            }
+            Ok(msg) => {
+                return Err(anyhow::anyhow!("Unexpected message on idle data channel: {:?}", msg))
+            }
+            Err(_) => {
+                // トンネルが閉じると待機中のデータチャネルも閉じられる
+                debug!("Idle data channel closed by server");
+                return Ok(());
+            }
+        }
+    }
+
+    serve_local(server_stream, &service, &events).await
+}
+
+/// 多重化した接続で次のストリームを待つ（多重化していなければ終わらない）
+/// セッションが終わると `None` を返す
+async fn accept_mux(mux: &mut Option<mux::Session>) -> Option<DuplexStream> {
+    match mux {
+        Some(mux) => mux.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// データチャネルをローカルサービスに接続して中継する
+async fn serve_local<S>(
+    server_stream: S,
+    service: &ServiceConfig,
+    events: &ServiceEvents,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    // ホスト名は接続のたびに名前解決するので、DNS やコンテナの IP の変更に追従できる
+    let local_addr = service.local_addr();
+    if service.protocol == ServiceProtocol::Udp {
+        relay_udp(server_stream, &local_addr, events).await?;
+        debug!("Data channel closed");
+        return Ok(());
+    }
+    match &service.local_socket {
+        #[cfg(unix)]
+        Some(path) => {
+            let local_stream = UnixStream::connect(path)
+                .await
+                .with_context(|| format!("Failed to connect to local service at {}", local_addr))?;
+            forward(server_stream, local_stream, events).await;
+        }
+        #[cfg(not(unix))]
+        Some(_) => {
+            return Err(anyhow::anyhow!(
+                "Unix domain sockets are not supported on this platform"
+            ));
+        }
+        None => {
+            let local_stream = TcpStream::connect(&local_addr)
+                .await
+                .with_context(|| format!("Failed to connect to local service at {}", local_addr))?;
+            forward(server_stream, local_stream, events).await;
+        }
+    }
+
+    debug!("Data channel closed");
+    Ok(())
+}
+
+/// UDP のフローをローカルサービスに中継する
+/// フローごとに別のソケットを使うので、ローカルサービスからは訪問者ごとに別の送信元に見える
+async fn relay_udp<S>(server_stream: S, local_addr: &str, events: &ServiceEvents) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let target = tokio::net::lookup_host(local_addr)
+        .await
+        .with_context(|| format!("Failed to resolve local service at {}", local_addr))?
+        .next()
+        .with_context(|| format!("No address found for local service at {}", local_addr))?;
+    let bind_addr = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind_addr)
         .await
-        .with_context(|| format!("Failed to connect to local service at port {}", local_port))?;
+        .context("Failed to bind UDP socket")?;
+    socket
+        .connect(target)
+        .await
+        .with_context(|| format!("Failed to connect to local service at {}", local_addr))?;
+    let socket = Arc::new(socket);
+
+    debug!("UDP data channel established to {}", target);
+
+    // ローカルサービスからの応答を中継ループに渡す
+    let (udp_tx, udp_rx) = mpsc::channel(udp::UDP_FLOW_QUEUE);
+    let receiver = {
+        let socket = socket.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; udp::MAX_DATAGRAM_SIZE];
+            loop {
+                match socket.recv(&mut buf).await {
+                    Ok(len) => {
+                        if udp_tx.send(buf[..len].to_vec()).await.is_err() {
+                            break;
+                        }
+                    }
+                    // ローカルサービスが落ちていると ICMP で失敗することがある
+                    Err(e) => debug!("Failed to receive from local service: {}", e),
+                }
+            }
+        })
+    };
 
+    events.send(TunnelEventKind::VisitorOpened);
+    let result = udp::relay(server_stream, socket, None, udp_rx).await;
+    receiver.abort();
+    events.send(TunnelEventKind::VisitorClosed);
+    result
+}
+
+/// サーバーとローカルサービスの間で双方向にコピーする
+async fn forward<S, L>(server_stream: S, local_stream: L, events: &ServiceEvents)
+where
+    S: AsyncRead + AsyncWrite,
+    L: AsyncRead + AsyncWrite,
+{
     debug!("Data channel established, starting bidirectional copy");
+    events.send(TunnelEventKind::VisitorOpened);
 
-    // 双方向コピー
     let (mut server_read, mut server_write) = tokio::io::split(server_stream);
     let (mut local_read, mut local_write) = tokio::io::split(local_stream);
 
@@ -213,7 +813,5 @@ async fn create_data_channel(remote_addr: String, local_port: u16) -> Result<()>
             }
         }
     }
-
-    debug!("Data channel closed");
-    Ok(())
+    events.send(TunnelEventKind::VisitorClosed);
 }
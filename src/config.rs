@@ -0,0 +1,961 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::warn;
+use url::Url;
+
+/// トランスポートの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportType {
+    /// 平文のTCP
+    #[default]
+    Tcp,
+    /// TLS (native-tls または rustls)
+    Tls,
+    /// Noise プロトコル (snowstorm)
+    Noise,
+    /// WebSocket (ws:// / wss://)
+    #[serde(alias = "ws")]
+    Websocket,
+}
+
+impl FromStr for TransportType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "tcp" => Ok(TransportType::Tcp),
+            "tls" => Ok(TransportType::Tls),
+            "noise" => Ok(TransportType::Noise),
+            "websocket" | "ws" => Ok(TransportType::Websocket),
+            _ => Err(format!(
+                "Unknown transport type: {} (expected tcp, tls, noise or websocket)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TransportType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportType::Tcp => write!(f, "tcp"),
+            TransportType::Tls => write!(f, "tls"),
+            TransportType::Noise => write!(f, "noise"),
+            TransportType::Websocket => write!(f, "websocket"),
+        }
+    }
+}
+
+/// TLS設定
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// クライアント: サーバー証明書の検証に追加で信頼するCA証明書 (PEM)
+    pub trusted_root: Option<String>,
+    /// クライアント: 証明書の検証に使うホスト名（省略時は接続先のホスト）
+    pub hostname: Option<String>,
+    /// サーバー: 証明書チェーン (PEM)
+    pub cert: Option<String>,
+    /// サーバー: 秘密鍵 (PEM, PKCS#8)
+    pub key: Option<String>,
+    /// サーバー: 証明書と秘密鍵を含む PKCS#12 アーカイブ（`cert`/`key` の代わり）
+    pub pkcs12: Option<String>,
+    /// サーバー: PKCS#12 アーカイブのパスワード
+    pub pkcs12_password: Option<String>,
+}
+
+/// Noise のデフォルトのハンドシェイクパターン
+pub const DEFAULT_NOISE_PATTERN: &str = "Noise_NK_25519_ChaChaPoly_BLAKE2s";
+
+/// Noise設定
+/// NK: サーバーのみ静的鍵を持ち、クライアントはサーバーの公開鍵を知っている
+/// KK: 双方が静的鍵を持ち、互いの公開鍵を知っている（相互認証）
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NoiseConfig {
+    /// ハンドシェイクパターン (例: Noise_NK_25519_ChaChaPoly_BLAKE2s)
+    pub pattern: String,
+    /// 自分の静的秘密鍵 (base64)。NK のクライアントでは不要
+    pub local_private_key: Option<String>,
+    /// 相手の静的公開鍵 (base64)。NK のサーバーでは不要
+    pub remote_public_key: Option<String>,
+}
+
+impl Default for NoiseConfig {
+    fn default() -> Self {
+        Self {
+            pattern: DEFAULT_NOISE_PATTERN.to_string(),
+            local_private_key: None,
+            remote_public_key: None,
+        }
+    }
+}
+
+/// WebSocket設定
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebsocketConfig {
+    /// サーバー: `tls` の証明書で wss:// として待ち受ける
+    /// クライアントは URL のスキーム (ws:// / wss://) で決まるので使わない
+    pub tls: bool,
+}
+
+/// トランスポート設定
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportConfig {
+    #[serde(rename = "type")]
+    pub transport_type: TransportType,
+    /// `transport_type` が `Tls` の場合に必要
+    pub tls: Option<TlsConfig>,
+    /// `transport_type` が `Noise` の場合に使う
+    pub noise: Option<NoiseConfig>,
+    /// `transport_type` が `Websocket` の場合に使う
+    pub websocket: Option<WebsocketConfig>,
+    /// クライアント: サーバーへの接続に使うプロキシ (http:// / socks5://)
+    pub proxy: Option<Url>,
+}
+
+impl TransportConfig {
+    /// サーバーとして使えるか確認
+    pub fn validate_server(&self) -> anyhow::Result<()> {
+        let websocket_tls = self.transport_type == TransportType::Websocket
+            && self.websocket.as_ref().map_or(false, |ws| ws.tls);
+        if self.transport_type == TransportType::Tls || websocket_tls {
+            let tls = self
+                .tls
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("TLS transport requires TLS configuration"))?;
+            if tls.pkcs12.is_none() && (tls.cert.is_none() || tls.key.is_none()) {
+                anyhow::bail!("TLS server requires either a PKCS#12 archive or a certificate and a key");
+            }
+        }
+        if self.transport_type == TransportType::Noise {
+            let has_key = self
+                .noise
+                .as_ref()
+                .map_or(false, |noise| noise.local_private_key.is_some());
+            if !has_key {
+                anyhow::bail!("Noise server requires a local private key");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// ポート範囲（両端を含む）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "PortRangeValue")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn new(start: u16, end: u16) -> Self {
+        Self { start, end }
+    }
+
+    /// 範囲内のポート
+    pub fn ports(&self) -> RangeInclusive<u16> {
+        self.start..=self.end
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    /// `35100-35199` または単一のポート `8080`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |p: &str| {
+            p.trim()
+                .parse::<u16>()
+                .ok()
+                .filter(|p| *p != 0)
+                .ok_or_else(|| format!("Invalid port: {}", p))
+        };
+        let range = match s.split_once('-') {
+            Some((start, end)) => PortRange::new(parse(start)?, parse(end)?),
+            None => {
+                let port = parse(s)?;
+                PortRange::new(port, port)
+            }
+        };
+        if range.start > range.end {
+            return Err(format!("Invalid port range: {}", s));
+        }
+        Ok(range)
+    }
+}
+
+/// 設定ファイルでのポート範囲: `8080` または `"35100-35199"`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortRangeValue {
+    Port(u16),
+    Range(String),
+}
+
+impl TryFrom<PortRangeValue> for PortRange {
+    type Error = String;
+
+    fn try_from(value: PortRangeValue) -> Result<Self, Self::Error> {
+        match value {
+            PortRangeValue::Port(port) => port.to_string().parse(),
+            PortRangeValue::Range(range) => range.parse(),
+        }
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+/// デフォルトのポート範囲
+pub const DEFAULT_PORT_RANGE: PortRange = PortRange {
+    start: 35100,
+    end: 35199,
+};
+
+/// 認証トークンとその制限
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenConfig {
+    pub token: String,
+    /// このトークンで同時に開けるトンネル数の上限
+    #[serde(default)]
+    pub max_tunnels: Option<usize>,
+    /// このトークンだけが使えるトンネル名
+    #[serde(default)]
+    pub reserved_names: Vec<String>,
+}
+
+impl TokenConfig {
+    /// 制限なしのトークンを作成
+    pub fn new(token: impl Into<String>) -> Self {
+        Self {
+            token: token.into(),
+            max_tunnels: None,
+            reserved_names: Vec::new(),
+        }
+    }
+}
+
+impl From<String> for TokenConfig {
+    fn from(token: String) -> Self {
+        TokenConfig::new(token)
+    }
+}
+
+/// 管理 API の設定
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// 待ち受けるアドレス。ループバックの `host:port` か、Unix ドメインソケットの `unix:/path`
+    pub bind_addr: String,
+    /// リクエストの `Authorization: Bearer <token>` で要求するトークン
+    pub token: String,
+}
+
+impl AdminConfig {
+    /// ループバックか Unix ドメインソケットでだけ待ち受けるか確認
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.token.is_empty() {
+            anyhow::bail!("Empty admin token in configuration");
+        }
+        if self.bind_addr.starts_with("unix:") {
+            if cfg!(not(unix)) {
+                anyhow::bail!("Unix domain sockets are not supported on this platform");
+            }
+            return Ok(());
+        }
+        let loopback = match self.bind_addr.parse::<SocketAddr>() {
+            Ok(addr) => addr.ip().is_loopback(),
+            Err(_) => self.bind_addr.starts_with("localhost:"),
+        };
+        if !loopback {
+            anyhow::bail!(
+                "Admin API must listen on a loopback address or a Unix domain socket, got {}",
+                self.bind_addr
+            );
+        }
+        Ok(())
+    }
+}
+
+/// サーバー設定
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// バインドアドレス (例: 0.0.0.0:2333)
+    pub bind_addr: String,
+    /// 受け付ける認証トークン。空の場合は認証なし
+    pub tokens: Vec<TokenConfig>,
+    /// コントロールチャネル・データチャネルのトランスポート
+    pub transport: TransportConfig,
+    /// 訪問者用に割り当てるポート範囲
+    pub port_ranges: Vec<PortRange>,
+    /// `port_ranges` のうち割り当てないポート
+    pub excluded_ports: Vec<PortRange>,
+    /// 訪問者用ポートを開くIPアドレス。`::` ならIPv4とIPv6の両方で待ち受ける
+    pub visitor_bind_ip: IpAddr,
+    /// 管理 API（省略時は無効）
+    pub admin: Option<AdminConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_addr: "0.0.0.0:2333".to_string(),
+            tokens: Vec::new(),
+            transport: TransportConfig::default(),
+            port_ranges: vec![DEFAULT_PORT_RANGE],
+            excluded_ports: Vec::new(),
+            visitor_bind_ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            admin: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// 認証なしの設定を作成
+    pub fn new(bind_addr: impl Into<String>) -> Self {
+        Self {
+            bind_addr: bind_addr.into(),
+            ..Default::default()
+        }
+    }
+
+    /// TOML の設定ファイルを読み込む
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let config: ServerConfig = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// トークンの設定を取得
+    pub fn token(&self, token: &str) -> Option<&TokenConfig> {
+        self.tokens.iter().find(|t| t.token == token)
+    }
+
+    /// 名前を予約しているトークン
+    pub fn name_owner(&self, name: &str) -> Option<&str> {
+        self.tokens
+            .iter()
+            .find(|t| t.reserved_names.iter().any(|n| n == name))
+            .map(|t| t.token.as_str())
+    }
+
+    /// 割り当て可能なポートの一覧（昇順・重複なし）
+    pub fn visitor_ports(&self) -> Vec<u16> {
+        let mut ports: Vec<u16> = self
+            .port_ranges
+            .iter()
+            .flat_map(|range| range.ports())
+            .filter(|port| !self.excluded_ports.iter().any(|ex| ex.ports().contains(port)))
+            .collect();
+        ports.sort_unstable();
+        ports.dedup();
+        ports
+    }
+
+    /// サーバーとして使えるか確認
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.transport.validate_server()?;
+        if self.visitor_ports().is_empty() {
+            anyhow::bail!("No ports left to assign after applying port ranges and exclusions");
+        }
+        if let Some(admin) = &self.admin {
+            admin.validate()?;
+        }
+
+        let mut tokens = HashSet::new();
+        let mut names = HashSet::new();
+        for token in &self.tokens {
+            if token.token.is_empty() {
+                anyhow::bail!("Empty token in configuration");
+            }
+            if !tokens.insert(token.token.as_str()) {
+                anyhow::bail!("Duplicate token in configuration");
+            }
+            for name in &token.reserved_names {
+                if !names.insert(name.as_str()) {
+                    anyhow::bail!("Name {} is reserved by more than one token", name);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// サービスのプロトコル
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceProtocol {
+    /// TCP
+    #[default]
+    Tcp,
+    /// UDP（データグラムはデータチャネル上で長さ付きのフレームとして運ぶ）
+    Udp,
+}
+
+impl ServiceProtocol {
+    /// TCP かどうか（プロトコルメッセージでは TCP のとき省略する）
+    pub fn is_tcp(&self) -> bool {
+        *self == ServiceProtocol::Tcp
+    }
+}
+
+impl FromStr for ServiceProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "tcp" => Ok(ServiceProtocol::Tcp),
+            "udp" => Ok(ServiceProtocol::Udp),
+            _ => Err(format!("Unknown protocol: {} (expected tcp or udp)", s)),
+        }
+    }
+}
+
+impl fmt::Display for ServiceProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceProtocol::Tcp => write!(f, "tcp"),
+            ServiceProtocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+fn default_local_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+/// 公開するサービス（1つのトンネル）の設定
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceConfig {
+    /// トンネル名。サーバーは名前ごとに前回のポートを覚えていて、再起動後も同じポートを返す
+    #[serde(default)]
+    pub name: Option<String>,
+    /// 転送先のホスト（IPアドレスまたはホスト名。ホスト名は接続のたびに名前解決する）
+    #[serde(default = "default_local_host")]
+    pub local_host: String,
+    /// 転送先のポート（`local_socket` を指定した場合は不要）
+    #[serde(default)]
+    pub local_port: u16,
+    /// 転送先の Unix ドメインソケットのパス。指定すると `local_host` / `local_port` の代わりに使う
+    #[serde(default)]
+    pub local_socket: Option<PathBuf>,
+    /// 希望するリモートポート（使えない場合は別のポートが割り当てられる）
+    #[serde(default)]
+    pub remote_port: Option<u16>,
+    /// プロトコル (tcp / udp)
+    #[serde(default)]
+    pub protocol: ServiceProtocol,
+}
+
+impl ServiceConfig {
+    /// 127.0.0.1 の指定ポートを公開するサービスを作成
+    pub fn new(local_port: u16) -> Self {
+        Self {
+            name: None,
+            local_host: default_local_host(),
+            local_port,
+            local_socket: None,
+            remote_port: None,
+            protocol: ServiceProtocol::Tcp,
+        }
+    }
+
+    /// 転送先のアドレス (host:port、Unix ドメインソケットなら unix:path)
+    pub fn local_addr(&self) -> String {
+        if let Some(path) = &self.local_socket {
+            format!("unix:{}", path.display())
+        } else if self.local_host.contains(':') && !self.local_host.starts_with('[') {
+            format!("[{}]:{}", self.local_host, self.local_port)
+        } else {
+            format!("{}:{}", self.local_host, self.local_port)
+        }
+    }
+
+    /// ログ用の表示名
+    pub fn display_name(&self) -> String {
+        match self.name.as_deref() {
+            Some(name) => name.to_string(),
+            None => self.local_addr(),
+        }
+    }
+}
+
+/// クライアント設定
+/// `services` のそれぞれが独立したコントロールチャネルを持ち、個別に再接続する
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientConfig {
+    /// サーバーアドレス (例: myserver.com:2333)
+    pub remote_addr: String,
+    /// 認証トークン（サーバーが認証を要求する場合に必要）
+    #[serde(default)]
+    pub token: Option<String>,
+    /// コントロールチャネル・データチャネルのトランスポート
+    #[serde(default)]
+    pub transport: TransportConfig,
+    /// 訪問者ごとに接続を張らず、1本の接続にストリームを多重化する
+    /// サーバーが対応していなければ訪問者ごとの接続に戻る
+    #[serde(default)]
+    pub multiplex: bool,
+    /// 訪問者を待つデータチャネルをサービスごとにこの数だけ前もって張っておく（0 なら使わない）
+    /// 訪問者の最初の1バイトまでの接続・ハンドシェイクの時間を省ける
+    #[serde(default)]
+    pub pool_size: u16,
+    /// 切断・接続失敗のあとの再接続の間隔
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// 接続・応答・ハートビートの時間
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    /// 公開するサービス
+    pub services: Vec<ServiceConfig>,
+}
+
+/// クライアントの再接続の設定
+/// 失敗するたびに間隔を `multiplier` 倍に広げ（上限 `max_interval_ms`）、
+/// サーバーの再起動で全クライアントが同時に再接続しないよう `jitter` の割合だけ間隔をばらつかせる
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// 最初の再接続までの間隔（ミリ秒）
+    pub initial_interval_ms: u64,
+    /// 再接続の間隔の上限（ミリ秒）
+    pub max_interval_ms: u64,
+    /// 失敗するたびに間隔を何倍にするか
+    pub multiplier: f64,
+    /// 間隔をばらつかせる割合（0.5 なら 0.5〜1.5 倍）
+    pub jitter: f64,
+    /// 最後に接続できてからこの秒数を過ぎたら再接続をあきらめる（省略時はあきらめない）
+    pub max_elapsed_secs: Option<u64>,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            initial_interval_ms: 1000,
+            max_interval_ms: 60_000,
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_elapsed_secs: None,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// 値の範囲を確認
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.initial_interval_ms == 0 {
+            anyhow::bail!("retry.initial_interval_ms must be greater than 0");
+        }
+        if self.max_interval_ms < self.initial_interval_ms {
+            anyhow::bail!(
+                "retry.max_interval_ms must not be less than retry.initial_interval_ms"
+            );
+        }
+        if !(1.0..).contains(&self.multiplier) {
+            anyhow::bail!("retry.multiplier must be at least 1");
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            anyhow::bail!("retry.jitter must be between 0 and 1");
+        }
+        Ok(())
+    }
+}
+
+/// クライアントのハートビートの間隔の上限（秒）
+/// サーバーは 60 秒何も届かなければ切断するので、1回届かなくても間に合うようその半分にする
+const MAX_HEARTBEAT_INTERVAL_SECS: u64 = 30;
+
+/// クライアントの接続・応答・ハートビートの時間（秒）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    /// サーバーへの接続（TLS・Noise のハンドシェイクを含む）を待つ時間
+    pub connect_secs: u64,
+    /// Hello・TunnelResponse などサーバーの応答を待つ時間
+    pub handshake_secs: u64,
+    /// ハートビートを送る間隔（30 秒まで）
+    /// サーバーは 60 秒何も届かなければ切断するので、それより十分短くする
+    pub heartbeat_interval_secs: u64,
+    /// この時間サーバーから何も届かなければ切断とみなして再接続する
+    pub heartbeat_timeout_secs: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            connect_secs: 10,
+            handshake_secs: 10,
+            heartbeat_interval_secs: 20,
+            heartbeat_timeout_secs: 60,
+        }
+    }
+}
+
+impl TimeoutConfig {
+    /// 値の範囲を確認
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.connect_secs == 0 || self.handshake_secs == 0 {
+            anyhow::bail!(
+                "timeouts.connect_secs and timeouts.handshake_secs must be greater than 0"
+            );
+        }
+        if self.heartbeat_interval_secs == 0 {
+            anyhow::bail!("timeouts.heartbeat_interval_secs must be greater than 0");
+        }
+        if self.heartbeat_interval_secs > MAX_HEARTBEAT_INTERVAL_SECS {
+            anyhow::bail!(
+                "timeouts.heartbeat_interval_secs must be at most {} (the server disconnects after 60 seconds of silence)",
+                MAX_HEARTBEAT_INTERVAL_SECS
+            );
+        }
+        if self.heartbeat_timeout_secs <= self.heartbeat_interval_secs {
+            anyhow::bail!(
+                "timeouts.heartbeat_timeout_secs must be greater than timeouts.heartbeat_interval_secs"
+            );
+        }
+        Ok(())
+    }
+}
+
+impl ClientConfig {
+    /// ローカルポートを1つ公開する認証なしの設定を作成
+    /// プロキシは環境変数 `ALL_PROXY` / `HTTPS_PROXY` があればそれを使う
+    pub fn new(remote_addr: impl Into<String>, local_port: u16) -> Self {
+        Self {
+            remote_addr: remote_addr.into(),
+            token: None,
+            transport: TransportConfig {
+                proxy: proxy_from_env(),
+                ..Default::default()
+            },
+            multiplex: false,
+            pool_size: 0,
+            retry: RetryConfig::default(),
+            timeouts: TimeoutConfig::default(),
+            services: vec![ServiceConfig::new(local_port)],
+        }
+    }
+
+    /// TOML の設定ファイルを読み込む
+    /// プロキシが書かれていなければ環境変数のものを使う
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let mut config: ClientConfig = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+        if config.transport.proxy.is_none() {
+            config.transport.proxy = proxy_from_env();
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// クライアントとして使えるか確認
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.services.is_empty() {
+            anyhow::bail!("No services configured");
+        }
+        self.retry.validate()?;
+        self.timeouts.validate()?;
+        let mut names = HashSet::new();
+        for service in &self.services {
+            if service.local_socket.is_some() {
+                if service.protocol == ServiceProtocol::Udp {
+                    anyhow::bail!(
+                        "Service {}: UDP services cannot forward to a Unix domain socket",
+                        service.display_name()
+                    );
+                }
+                if cfg!(not(unix)) {
+                    anyhow::bail!(
+                        "Service {}: Unix domain sockets are not supported on this platform",
+                        service.display_name()
+                    );
+                }
+            } else if service.local_port == 0 {
+                anyhow::bail!("Service {} has no local port", service.display_name());
+            }
+            if let Some(name) = service.name.as_deref() {
+                if !names.insert(name) {
+                    anyhow::bail!("Service name {} is used more than once", name);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 環境変数からプロキシを読み込む（大文字を優先）
+fn proxy_from_env() -> Option<Url> {
+    ["ALL_PROXY", "all_proxy", "HTTPS_PROXY", "https_proxy"]
+        .iter()
+        .find_map(|name| {
+            let value = env::var(name).ok().filter(|v| !v.is_empty())?;
+            match Url::parse(&value) {
+                Ok(url) => Some(url),
+                Err(e) => {
+                    warn!("Ignoring invalid proxy in {}: {}", name, e);
+                    None
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_port_range() {
+        assert_eq!("35100-35199".parse(), Ok(PortRange::new(35100, 35199)));
+        assert_eq!("8080".parse(), Ok(PortRange::new(8080, 8080)));
+        assert!("35199-35100".parse::<PortRange>().is_err());
+        assert!("0-10".parse::<PortRange>().is_err());
+        assert!("abc".parse::<PortRange>().is_err());
+    }
+
+    #[test]
+    fn test_parse_server_config() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            bind_addr = "0.0.0.0:2444"
+            port_ranges = ["20000-20009", 20100]
+            excluded_ports = [20005]
+            visitor_bind_ip = "::"
+
+            [transport]
+            type = "noise"
+
+            [transport.noise]
+            local_private_key = "key"
+
+            [admin]
+            bind_addr = "127.0.0.1:2334"
+            token = "admin-secret"
+
+            [[tokens]]
+            token = "alpha"
+            max_tunnels = 2
+            reserved_names = ["web"]
+
+            [[tokens]]
+            token = "beta"
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.bind_addr, "0.0.0.0:2444");
+        assert_eq!(config.visitor_ports().len(), 10);
+        assert_eq!(config.visitor_bind_ip, "::".parse::<IpAddr>().unwrap());
+        assert_eq!(config.transport.transport_type, TransportType::Noise);
+        let noise = config.transport.noise.as_ref().unwrap();
+        assert_eq!(noise.pattern, DEFAULT_NOISE_PATTERN);
+        assert_eq!(config.token("alpha").unwrap().max_tunnels, Some(2));
+        assert_eq!(config.token("beta"), Some(&TokenConfig::new("beta")));
+        assert_eq!(config.name_owner("web"), Some("alpha"));
+        assert_eq!(config.name_owner("ssh"), None);
+        assert_eq!(config.admin.as_ref().unwrap().bind_addr, "127.0.0.1:2334");
+    }
+
+    #[test]
+    fn test_server_config_defaults_and_errors() {
+        let config: ServerConfig = toml::from_str("").unwrap();
+        assert_eq!(config.bind_addr, "0.0.0.0:2333");
+        assert_eq!(config.port_ranges, vec![DEFAULT_PORT_RANGE]);
+        assert_eq!(config.admin, None);
+
+        // 管理 API はループバックか Unix ドメインソケットでだけ待ち受ける
+        let mut config = ServerConfig::default();
+        let admin = |bind_addr: &str| AdminConfig {
+            bind_addr: bind_addr.to_string(),
+            token: "admin-secret".to_string(),
+        };
+        config.admin = Some(admin("0.0.0.0:2334"));
+        assert!(config.validate().is_err());
+        config.admin = Some(admin("[::1]:2334"));
+        config.validate().unwrap();
+        config.admin = Some(admin("localhost:2334"));
+        config.validate().unwrap();
+
+        // 未知のキー・重複した予約名はエラー
+        assert!(toml::from_str::<ServerConfig>("bind = \"0.0.0.0:1\"").is_err());
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [[tokens]]
+            token = "alpha"
+            reserved_names = ["web"]
+
+            [[tokens]]
+            token = "beta"
+            reserved_names = ["web"]
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_parse_client_config() {
+        let config: ClientConfig = toml::from_str(
+            r#"
+            remote_addr = "myserver.com:2333"
+            token = "alpha"
+            multiplex = true
+            pool_size = 4
+
+            [retry]
+            initial_interval_ms = 500
+            max_elapsed_secs = 600
+
+            [timeouts]
+            connect_secs = 5
+            heartbeat_interval_secs = 10
+
+            [[services]]
+            name = "web"
+            local_port = 8080
+            remote_port = 35100
+
+            [[services]]
+            local_host = "::1"
+            local_port = 22
+            protocol = "tcp"
+
+            [[services]]
+            name = "dns"
+            local_port = 53
+            protocol = "udp"
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        assert!(config.multiplex);
+        assert_eq!(config.pool_size, 4);
+        assert_eq!(config.retry.initial_interval_ms, 500);
+        assert_eq!(config.retry.max_interval_ms, 60_000);
+        assert_eq!(config.retry.max_elapsed_secs, Some(600));
+        assert_eq!(config.timeouts.connect_secs, 5);
+        assert_eq!(config.timeouts.handshake_secs, 10);
+        assert_eq!(config.timeouts.heartbeat_interval_secs, 10);
+        assert_eq!(config.timeouts.heartbeat_timeout_secs, 60);
+        assert_eq!(config.services.len(), 3);
+        assert_eq!(config.services[0].local_addr(), "127.0.0.1:8080");
+        assert_eq!(config.services[0].remote_port, Some(35100));
+        assert_eq!(config.services[0].display_name(), "web");
+        assert_eq!(config.services[1].local_addr(), "[::1]:22");
+        assert_eq!(config.services[1].display_name(), "[::1]:22");
+        assert_eq!(config.services[1].protocol, ServiceProtocol::Tcp);
+        assert_eq!(config.services[2].protocol, ServiceProtocol::Udp);
+
+        // サービスなし・名前の重複はエラー
+        let mut config = ClientConfig::new("myserver.com:2333", 8080);
+        config.services.clear();
+        assert!(config.validate().is_err());
+        let mut service = ServiceConfig::new(8080);
+        service.name = Some("web".to_string());
+        config.services = vec![service.clone(), service];
+        assert!(config.validate().is_err());
+
+        // 再接続の間隔が逆転している・ばらつきが範囲外ならエラー
+        let mut config = ClientConfig::new("myserver.com:2333", 8080);
+        config.retry.max_interval_ms = 100;
+        assert!(config.validate().is_err());
+        let mut config = ClientConfig::new("myserver.com:2333", 8080);
+        config.retry.jitter = 1.5;
+        assert!(config.validate().is_err());
+
+        // ハートビートの間隔より短いタイムアウトはエラー
+        let mut config = ClientConfig::new("myserver.com:2333", 8080);
+        config.timeouts.heartbeat_timeout_secs = 20;
+        assert!(config.validate().is_err());
+
+        // サーバーのタイムアウト（60 秒）に間に合わない間隔はエラー
+        let mut config = ClientConfig::new("myserver.com:2333", 8080);
+        config.timeouts.heartbeat_interval_secs = 60;
+        config.timeouts.heartbeat_timeout_secs = 120;
+        assert!(config.validate().is_err());
+        config.timeouts.heartbeat_interval_secs = 30;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_service_local_targets() {
+        let config: ClientConfig = toml::from_str(
+            r#"
+            remote_addr = "myserver.com:2333"
+
+            [[services]]
+            local_host = "db.internal"
+            local_port = 5432
+
+            [[services]]
+            local_host = "[fd00::20]"
+            local_port = 80
+
+            [[services]]
+            local_socket = "/var/run/app.sock"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.services[0].local_addr(), "db.internal:5432");
+        assert_eq!(config.services[1].local_addr(), "[fd00::20]:80");
+        assert_eq!(config.services[2].local_addr(), "unix:/var/run/app.sock");
+        #[cfg(unix)]
+        config.validate().unwrap();
+
+        // UDP は Unix ドメインソケットに転送できない
+        let mut udp = config.clone();
+        udp.services[2].protocol = ServiceProtocol::Udp;
+        assert!(udp.validate().is_err());
+
+        // ポートもソケットもなければエラー
+        let mut config = ClientConfig::new("myserver.com:2333", 0);
+        assert!(config.validate().is_err());
+        config.services[0].local_host = "192.168.1.20".to_string();
+        config.services[0].local_port = 22;
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_visitor_ports() {
+        let mut config = ServerConfig::new("0.0.0.0:2333");
+        config.port_ranges = vec![PortRange::new(100, 105), PortRange::new(103, 107)];
+        config.excluded_ports = vec![PortRange::new(101, 102), PortRange::new(106, 106)];
+        assert_eq!(config.visitor_ports(), vec![100, 103, 104, 105, 107]);
+
+        config.excluded_ports = vec![PortRange::new(1, 65535)];
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::{Context, Result};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tracing::{error, info};
+
+use crate::config::ServerConfig;
+
+/// 連続した書き込みをまとめるための待ち時間
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// 設定ファイルを監視し、変更されたら読み込み直して `config_tx` に送る
+/// 読み込みに失敗した場合はエラーを出力して古い設定を使い続ける
+pub(crate) async fn watch_config(
+    path: PathBuf,
+    config_tx: watch::Sender<Arc<ServerConfig>>,
+) -> Result<()> {
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+    let file_name = path.file_name().map(|name| name.to_os_string());
+
+    // エディタは一時ファイルを作って置き換えることがあるので、ディレクトリごと監視する
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if let Ok(event) = res {
+            if matches!(event.kind, EventKind::Access(_)) {
+                return;
+            }
+            if event
+                .paths
+                .iter()
+                .any(|p| p.file_name().map(|name| name.to_os_string()) == file_name)
+            {
+                let _ = event_tx.send(());
+            }
+        }
+    })
+    .context("Failed to create config file watcher")?;
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .with_context(|| format!("Failed to watch {}", dir.display()))?;
+    info!("Watching {} for changes", path.display());
+
+    while event_rx.recv().await.is_some() {
+        tokio::time::sleep(DEBOUNCE).await;
+        while event_rx.try_recv().is_ok() {}
+
+        let config = match ServerConfig::from_file(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Failed to reload {}, keeping the old config: {:#}", path.display(), e);
+                continue;
+            }
+        };
+        if **config_tx.borrow() == config {
+            continue;
+        }
+        info!("Reloaded {}", path.display());
+        config_tx.send_replace(Arc::new(config));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reload_on_change() {
+        let dir = std::env::temp_dir().join(format!("rathole-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.toml");
+        std::fs::write(&path, "bind_addr = \"127.0.0.1:2333\"\n").unwrap();
+
+        let (config_tx, mut config_rx) =
+            watch::channel(Arc::new(ServerConfig::from_file(&path).unwrap()));
+        let watcher = tokio::spawn(watch_config(path.clone(), config_tx));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // 不正な設定は無視される
+        std::fs::write(&path, "bind_addr = 1\n").unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!config_rx.has_changed().unwrap());
+
+        std::fs::write(&path, "bind_addr = \"127.0.0.1:2444\"\n").unwrap();
+        tokio::time::timeout(Duration::from_secs(5), config_rx.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(config_rx.borrow().bind_addr, "127.0.0.1:2444");
+
+        watcher.abort();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
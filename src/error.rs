@@ -0,0 +1,218 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::protocol::ErrorCode;
+
+/// ライブラリの公開 API が返すエラー
+/// 元のエラーは `std::error::Error::source` で取り出せる
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum Error {
+    /// 設定が正しくない（証明書や鍵が読めないなど）。直さない限り成功しない
+    Config(Arc<anyhow::Error>),
+    /// サーバーに接続できない・接続が切れた（サーバーの停止やネットワークの問題）
+    ConnectionRefused(Arc<anyhow::Error>),
+    /// ハンドシェイクが時間内に終わらなかった
+    HandshakeTimeout(Arc<anyhow::Error>),
+    /// 相手が想定外のメッセージを送ってきた
+    Protocol(Arc<anyhow::Error>),
+    /// 認証に失敗した（トークンがない・間違っている・失効した）
+    AuthRejected(TunnelError),
+    /// 訪問者用のポートを用意できなかった（ポートの枯渇・予約された名前・トンネル数の上限など）
+    PortUnavailable(TunnelError),
+    /// サーバーがその他の理由でトンネルを拒否・終了した（同じ名前のトンネルによる置き換え・プロトコルバージョンの不一致など）
+    Rejected(TunnelError),
+    /// 待ち受けるポートを開けないなどの I/O エラー
+    Io(Arc<anyhow::Error>),
+    /// トンネル・サーバーが止まった（シャットダウン・内部タスクの異常終了）
+    Shutdown(Arc<anyhow::Error>),
+}
+
+/// サーバーがトンネルを拒否・終了したときのエラー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelError {
+    /// エラーの種類
+    pub code: ErrorCode,
+    /// サーバーからの説明
+    pub message: String,
+    /// 時間をおいて再接続すれば成功する見込みがあるか（false ならクライアントは再接続しない）
+    pub retryable: bool,
+}
+
+impl Error {
+    /// 時間をおいてやり直せば成功する見込みがあるか
+    /// false なら設定・トークンなどを直す必要がある
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::ConnectionRefused(_) | Error::HandshakeTimeout(_) | Error::Io(_) => true,
+            Error::PortUnavailable(rejection) | Error::Rejected(rejection) => rejection.retryable,
+            Error::Config(_) | Error::Protocol(_) | Error::AuthRejected(_) | Error::Shutdown(_) => {
+                false
+            }
+        }
+    }
+
+    /// サーバーが返した拒否の理由
+    pub fn rejection(&self) -> Option<&TunnelError> {
+        match self {
+            Error::AuthRejected(rejection)
+            | Error::PortUnavailable(rejection)
+            | Error::Rejected(rejection) => Some(rejection),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn config(err: impl Into<anyhow::Error>) -> Self {
+        Error::Config(Arc::new(err.into()))
+    }
+
+    pub(crate) fn connection(err: impl Into<anyhow::Error>) -> Self {
+        Error::ConnectionRefused(Arc::new(err.into()))
+    }
+
+    pub(crate) fn io(err: impl Into<anyhow::Error>) -> Self {
+        Error::Io(Arc::new(err.into()))
+    }
+
+    pub(crate) fn shutdown(err: impl Into<anyhow::Error>) -> Self {
+        Error::Shutdown(Arc::new(err.into()))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(e) => write!(f, "Invalid configuration: {:#}", e),
+            Error::ConnectionRefused(e) => write!(f, "Cannot connect to server: {:#}", e),
+            Error::HandshakeTimeout(e) => write!(f, "Handshake timed out: {:#}", e),
+            Error::Protocol(e) => write!(f, "Protocol error: {:#}", e),
+            Error::AuthRejected(rejection)
+            | Error::PortUnavailable(rejection)
+            | Error::Rejected(rejection) => fmt::Display::fmt(rejection, f),
+            Error::Io(e) => write!(f, "I/O error: {:#}", e),
+            Error::Shutdown(e) => write!(f, "Stopped: {:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Config(e)
+            | Error::ConnectionRefused(e)
+            | Error::HandshakeTimeout(e)
+            | Error::Protocol(e)
+            | Error::Io(e)
+            | Error::Shutdown(e) => Some(&***e),
+            Error::AuthRejected(rejection)
+            | Error::PortUnavailable(rejection)
+            | Error::Rejected(rejection) => Some(rejection),
+        }
+    }
+}
+
+impl From<TunnelError> for Error {
+    fn from(rejection: TunnelError) -> Self {
+        match rejection.code {
+            ErrorCode::AuthFailed | ErrorCode::TokenRevoked => Error::AuthRejected(rejection),
+            ErrorCode::PortsExhausted
+            | ErrorCode::BindFailed
+            | ErrorCode::NameReserved
+            | ErrorCode::TunnelLimit => Error::PortUnavailable(rejection),
+            _ => Error::Rejected(rejection),
+        }
+    }
+}
+
+/// 内部の `anyhow::Error` を分類する
+/// 発生元で分類済みのもの・サーバーの拒否・タイムアウト・I/O エラーの順に見て、残りはプロトコルエラーとする
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        if let Some(classified) = err.downcast_ref::<Error>() {
+            return classified.clone();
+        }
+        if let Some(rejection) = err.downcast_ref::<TunnelError>() {
+            return rejection.clone().into();
+        }
+        if err.downcast_ref::<tokio::time::error::Elapsed>().is_some() {
+            return Error::HandshakeTimeout(Arc::new(err));
+        }
+        if err.chain().any(|cause| cause.is::<std::io::Error>()) {
+            return Error::connection(err);
+        }
+        Error::Protocol(Arc::new(err))
+    }
+}
+
+impl fmt::Display for TunnelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Tunnel rejected by server: {}", self.message)
+    }
+}
+
+impl std::error::Error for TunnelError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    fn rejection(code: ErrorCode) -> TunnelError {
+        TunnelError {
+            code,
+            message: code.to_string(),
+            retryable: code.is_retryable(),
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        // 発生元で分類済みなら文脈を足しても同じ種類
+        let err = anyhow::Error::new(Error::config(anyhow::anyhow!("bad certificate")))
+            .context("Failed to start");
+        assert!(matches!(Error::from(err), Error::Config(_)));
+
+        let err = anyhow::Error::new(rejection(ErrorCode::AuthFailed));
+        let err = Error::from(err);
+        assert!(matches!(err, Error::AuthRejected(_)));
+        assert!(!err.is_transient());
+        assert_eq!(err.rejection().unwrap().code, ErrorCode::AuthFailed);
+
+        let err = Error::from(anyhow::Error::new(rejection(ErrorCode::PortsExhausted)));
+        assert!(matches!(err, Error::PortUnavailable(_)));
+        assert!(err.is_transient());
+
+        let err = Error::from(
+            Err::<(), _>(std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+                .context("Failed to connect")
+                .unwrap_err(),
+        );
+        assert!(matches!(err, Error::ConnectionRefused(_)));
+        assert!(err.is_transient());
+
+        let err = Error::from(anyhow::anyhow!("Unexpected response from server"));
+        assert!(matches!(err, Error::Protocol(_)));
+        assert!(!err.is_transient());
+    }
+
+    #[tokio::test]
+    async fn test_classify_timeout() {
+        let elapsed = tokio::time::timeout(
+            std::time::Duration::from_millis(1),
+            std::future::pending::<()>(),
+        )
+        .await
+        .unwrap_err();
+        let err = Error::from(anyhow::Error::new(elapsed).context("Timeout waiting for Hello"));
+        assert!(matches!(err, Error::HandshakeTimeout(_)));
+    }
+
+    #[test]
+    fn test_source() {
+        use std::error::Error as _;
+
+        let err = Error::from(rejection(ErrorCode::Replaced));
+        assert!(matches!(err, Error::Rejected(_)));
+        assert!(err.source().unwrap().is::<TunnelError>());
+    }
+}
@@ -1,12 +1,38 @@
 // 新しいシンプルなrathole実装
 // 設定ファイル不要、CLIのみでトンネルを確立
 
+mod admin;
+mod auth;
+mod config;
+#[cfg(feature = "hot-reload")]
+mod config_watcher;
+mod error;
+mod mux;
 mod protocol;
 mod port_allocator;
 mod client;
 mod server;
+mod server_handle;
+mod stats;
+mod transport;
 mod tunnel;
+mod udp;
 
 // パブリックAPI
-pub use tunnel::{start_tunnel, Tunnel};
-pub use server::run_server;
+pub use config::{
+    AdminConfig, ClientConfig, NoiseConfig, PortRange, RetryConfig, ServerConfig, ServiceConfig,
+    ServiceProtocol, TimeoutConfig, TlsConfig, TokenConfig, TransportConfig, TransportType,
+    WebsocketConfig, DEFAULT_NOISE_PATTERN, DEFAULT_PORT_RANGE,
+};
+pub use admin::{AdminClient, TunnelSummary};
+pub use port_allocator::PortUsage;
+pub use protocol::ErrorCode;
+pub use error::{Error, TunnelError};
+pub use tunnel::{
+    start_tunnel, start_tunnel_with_config, ServiceInfo, Tunnel, TunnelBuilder, TunnelEvent,
+    TunnelEventKind, TunnelState,
+};
+pub use server::{run_server, run_server_with_config, run_server_with_config_file};
+pub use server_handle::{ServerBuilder, ServerEvent, ServerHandle};
+#[cfg(feature = "noise")]
+pub use transport::generate_noise_keypair;
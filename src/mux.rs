@@ -0,0 +1,535 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+/// ストリームごとの送信ウィンドウの初期値
+/// 受信側がアプリケーションに渡した分だけ WINDOW フレームで広げる
+const INITIAL_WINDOW: u32 = 256 * 1024;
+
+/// DATA フレームの最大ペイロード
+const MAX_DATA_PAYLOAD: usize = 16 * 1024;
+
+/// アプリケーションとの間のバッファ
+const STREAM_BUFFER: usize = 64 * 1024;
+
+/// フレームが大きすぎる場合はエラー（DoS対策）
+const MAX_FRAME_LEN: u32 = 1024 * 1024;
+
+/// 新しいストリームを開く
+const OPEN: u8 = 0;
+/// ストリームのデータ
+const DATA: u8 = 1;
+/// 送信ウィンドウを広げる（ペイロードは u32 little-endian の増分）
+const WINDOW: u8 = 2;
+/// これ以上データを送らない（片方向のクローズ）
+const CLOSE: u8 = 3;
+
+/// どちら側のセッションか。ストリームIDが衝突しないよう偶奇を分ける
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    /// 奇数のストリームIDを使う
+    Client,
+    /// 偶数のストリームIDを使う
+    Server,
+}
+
+/// 多重化した接続上のフレーム
+/// フォーマット: [stream_id: u32 LE][kind: u8][length: u32 LE][payload]
+#[derive(Debug, PartialEq, Eq)]
+struct Frame {
+    stream_id: u32,
+    kind: u8,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn new(stream_id: u32, kind: u8, payload: Vec<u8>) -> Self {
+        Self {
+            stream_id,
+            kind,
+            payload,
+        }
+    }
+
+    async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        let mut buf = Vec::with_capacity(9 + self.payload.len());
+        buf.extend_from_slice(&self.stream_id.to_le_bytes());
+        buf.push(self.kind);
+        buf.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.payload);
+        writer
+            .write_all(&buf)
+            .await
+            .context("Failed to write frame")?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self> {
+        let stream_id = reader
+            .read_u32_le()
+            .await
+            .context("Failed to read frame header")?;
+        let kind = reader.read_u8().await.context("Failed to read frame header")?;
+        let len = reader
+            .read_u32_le()
+            .await
+            .context("Failed to read frame header")?;
+        if len > MAX_FRAME_LEN {
+            anyhow::bail!("Frame too large: {} bytes", len);
+        }
+        let mut payload = vec![0u8; len as usize];
+        reader
+            .read_exact(&mut payload)
+            .await
+            .context("Failed to read frame payload")?;
+        Ok(Self::new(stream_id, kind, payload))
+    }
+}
+
+/// ストリームごとの状態
+struct StreamEntry {
+    /// 相手から届いたデータの受け渡し先（CLOSE を受け取ると None）
+    inbound: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    /// 相手がまだ受け取れるバイト数
+    send_window: Arc<Semaphore>,
+    /// 相手にまだ送ってよいと伝えてあるバイト数（超えて送ってきたらプロトコル違反）
+    recv_window: u32,
+    /// こちらから CLOSE を送り終えたか
+    outbound_done: bool,
+}
+
+/// セッションとストリームで共有する状態
+struct Shared {
+    frame_tx: mpsc::Sender<Frame>,
+    streams: Mutex<HashMap<u32, StreamEntry>>,
+    next_id: AtomicU32,
+}
+
+impl Shared {
+    /// ストリームを登録して、受信データと送信ウィンドウを返す
+    fn register(&self, id: u32) -> (mpsc::UnboundedReceiver<Vec<u8>>, Arc<Semaphore>) {
+        let (inbound_tx, inbound_rx) = mpsc::unbounded_channel();
+        let send_window = Arc::new(Semaphore::new(INITIAL_WINDOW as usize));
+        self.streams.lock().unwrap().insert(
+            id,
+            StreamEntry {
+                inbound: Some(inbound_tx),
+                send_window: send_window.clone(),
+                recv_window: INITIAL_WINDOW,
+                outbound_done: false,
+            },
+        );
+        (inbound_rx, send_window)
+    }
+
+    /// アプリケーションに渡した分だけ相手に送ってよいバイト数を増やす（WINDOW を送る前に呼ぶ）
+    fn grant(&self, id: u32, increment: u32) {
+        if let Some(entry) = self.streams.lock().unwrap().get_mut(&id) {
+            entry.recv_window = entry.recv_window.saturating_add(increment);
+        }
+    }
+
+    /// 片方向が終わったことを記録し、両方向とも終わったストリームを取り除く
+    fn finish(&self, id: u32, inbound: bool) {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(entry) = streams.get_mut(&id) {
+            if inbound {
+                entry.inbound = None;
+            } else {
+                entry.outbound_done = true;
+            }
+            if entry.inbound.is_none() && entry.outbound_done {
+                streams.remove(&id);
+            }
+        }
+    }
+
+    /// すべてのストリームを閉じる（セッションの終了時）
+    fn close_all(&self) {
+        for (_, entry) in self.streams.lock().unwrap().drain() {
+            entry.send_window.close();
+        }
+    }
+}
+
+/// 1本の接続の上でストリームを多重化するセッション
+/// ストリームは `DuplexStream` としてアプリケーションに渡す
+pub struct Session {
+    control: Control,
+    incoming: mpsc::Receiver<DuplexStream>,
+    handle: JoinHandle<()>,
+}
+
+impl Session {
+    /// 接続の上でセッションを開始
+    pub fn new<S>(conn: S, side: Side) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (frame_tx, mut frame_rx) = mpsc::channel::<Frame>(64);
+        let (incoming_tx, incoming) = mpsc::channel(32);
+        let shared = Arc::new(Shared {
+            frame_tx,
+            streams: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(match side {
+                Side::Client => 1,
+                Side::Server => 2,
+            }),
+        });
+
+        let driver_shared = shared.clone();
+        let handle = tokio::spawn(async move {
+            let shared = driver_shared;
+            let (mut read_half, mut write_half) = tokio::io::split(conn);
+            let writer = async {
+                while let Some(frame) = frame_rx.recv().await {
+                    if let Err(e) = frame.write_to(&mut write_half).await {
+                        debug!("Multiplexed connection write error: {}", e);
+                        break;
+                    }
+                }
+            };
+            let reader = async {
+                loop {
+                    let frame = match Frame::read_from(&mut read_half).await {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            debug!("Multiplexed connection read error: {}", e);
+                            break;
+                        }
+                    };
+                    if let Err(e) = dispatch(&shared, &incoming_tx, frame).await {
+                        debug!("Multiplexed connection protocol error: {}", e);
+                        break;
+                    }
+                }
+            };
+            // どちらかが終われば接続は使えない。frame_rx を捨てると `closed` が完了する
+            tokio::select! {
+                _ = writer => {}
+                _ = reader => {}
+            }
+            shared.close_all();
+        });
+
+        Self {
+            control: Control { shared },
+            incoming,
+            handle,
+        }
+    }
+
+    /// ストリームを開くためのハンドルを取得
+    pub fn control(&self) -> Control {
+        self.control.clone()
+    }
+
+    /// 相手が開いたストリームを受け取る（キャンセル安全）
+    /// セッションが終わると `None` を返す
+    pub async fn accept(&mut self) -> Option<DuplexStream> {
+        self.incoming.recv().await
+    }
+
+    /// 相手が開いたストリームを受け付けず、すぐに閉じる（`accept` を呼ばない側で使う）
+    pub fn reject_incoming(&mut self) {
+        self.incoming.close();
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.handle.abort();
+        self.control.shared.close_all();
+    }
+}
+
+/// ストリームを開くためのハンドル
+#[derive(Clone)]
+pub struct Control {
+    shared: Arc<Shared>,
+}
+
+impl Control {
+    /// 新しいストリームを開く。相手の確認は待たない
+    pub async fn open(&self) -> Result<DuplexStream> {
+        let id = self.shared.next_id.fetch_add(2, Ordering::Relaxed);
+        // 相手からの応答を取りこぼさないよう、OPEN を送る前に登録する
+        let (inbound_rx, send_window) = self.shared.register(id);
+        if self
+            .shared
+            .frame_tx
+            .send(Frame::new(id, OPEN, Vec::new()))
+            .await
+            .is_err()
+        {
+            self.shared.streams.lock().unwrap().remove(&id);
+            anyhow::bail!("Multiplexed connection closed");
+        }
+        Ok(spawn_stream(self.shared.clone(), id, inbound_rx, send_window))
+    }
+
+    /// セッションが終わるまで待つ（キャンセル安全）
+    pub async fn closed(&self) {
+        self.shared.frame_tx.closed().await
+    }
+}
+
+/// 受信したフレームを処理
+async fn dispatch(
+    shared: &Arc<Shared>,
+    incoming_tx: &mpsc::Sender<DuplexStream>,
+    frame: Frame,
+) -> Result<()> {
+    let id = frame.stream_id;
+    match frame.kind {
+        OPEN => {
+            if shared.streams.lock().unwrap().contains_key(&id) {
+                anyhow::bail!("Stream {} opened twice", id);
+            }
+            // 受け付けていない・受け取る側が追いついていなければ、読み取りを止めずにすぐ閉じる
+            let Ok(permit) = incoming_tx.try_reserve() else {
+                debug!("Refusing stream {}", id);
+                shared
+                    .frame_tx
+                    .send(Frame::new(id, CLOSE, Vec::new()))
+                    .await
+                    .context("Multiplexed connection closed")?;
+                return Ok(());
+            };
+            let (inbound_rx, send_window) = shared.register(id);
+            permit.send(spawn_stream(shared.clone(), id, inbound_rx, send_window));
+        }
+        DATA => {
+            let mut streams = shared.streams.lock().unwrap();
+            if let Some(entry) = streams.get_mut(&id) {
+                // ウィンドウを無視して送ってくる相手にはバッファを際限なく使わせない
+                let len = frame.payload.len() as u32;
+                if len > entry.recv_window {
+                    anyhow::bail!("Stream {} exceeded its receive window", id);
+                }
+                entry.recv_window -= len;
+                if let Some(inbound) = entry.inbound.as_ref() {
+                    let _ = inbound.send(frame.payload);
+                }
+            }
+        }
+        WINDOW => {
+            let increment: [u8; 4] = frame
+                .payload
+                .as_slice()
+                .try_into()
+                .context("Invalid WINDOW frame")?;
+            let streams = shared.streams.lock().unwrap();
+            if let Some(entry) = streams.get(&id) {
+                entry
+                    .send_window
+                    .add_permits(u32::from_le_bytes(increment) as usize);
+            }
+        }
+        CLOSE => {
+            // 受信側のタスクは溜まったデータを渡し終えてから終わる
+            if let Some(entry) = shared.streams.lock().unwrap().get_mut(&id) {
+                entry.inbound = None;
+            }
+        }
+        kind => anyhow::bail!("Unknown frame kind {}", kind),
+    }
+    Ok(())
+}
+
+/// ストリームの送受信タスクを起動して、アプリケーション側の端を返す
+fn spawn_stream(
+    shared: Arc<Shared>,
+    id: u32,
+    mut inbound_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    send_window: Arc<Semaphore>,
+) -> DuplexStream {
+    let (app, pipe) = tokio::io::duplex(STREAM_BUFFER);
+    let (mut pipe_read, mut pipe_write) = tokio::io::split(pipe);
+
+    // 相手 → アプリケーション
+    let inbound_shared = shared.clone();
+    tokio::spawn(async move {
+        let shared = inbound_shared;
+        while let Some(data) = inbound_rx.recv().await {
+            if pipe_write.write_all(&data).await.is_err() {
+                break;
+            }
+            // バッファに移せた分だけ相手の送信ウィンドウを広げる
+            shared.grant(id, data.len() as u32);
+            let increment = (data.len() as u32).to_le_bytes().to_vec();
+            if shared
+                .frame_tx
+                .send(Frame::new(id, WINDOW, increment))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+        let _ = pipe_write.shutdown().await;
+        shared.finish(id, true);
+    });
+
+    // アプリケーション → 相手
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; MAX_DATA_PAYLOAD];
+        loop {
+            let n = match pipe_read.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            // 相手が受け取れるようになるまで待つ
+            match send_window.acquire_many(n as u32).await {
+                Ok(permit) => permit.forget(),
+                Err(_) => break,
+            }
+            if shared
+                .frame_tx
+                .send(Frame::new(id, DATA, buf[..n].to_vec()))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+        let _ = shared.frame_tx.send(Frame::new(id, CLOSE, Vec::new())).await;
+        shared.finish(id, false);
+    });
+
+    app
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_frame_roundtrip() {
+        let frame = Frame::new(7, DATA, b"hello".to_vec());
+        let mut buf = Vec::new();
+        frame.write_to(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), 9 + 5);
+
+        let mut reader = buf.as_slice();
+        assert_eq!(Frame::read_from(&mut reader).await.unwrap(), frame);
+    }
+
+    #[tokio::test]
+    async fn test_streams() {
+        let (a, b) = tokio::io::duplex(1024);
+        let server = Session::new(a, Side::Server);
+        let mut client = Session::new(b, Side::Client);
+
+        // 2本のストリームを並行して使う
+        let mut s1 = server.control().open().await.unwrap();
+        let mut s2 = server.control().open().await.unwrap();
+        let mut c1 = client.accept().await.unwrap();
+        let mut c2 = client.accept().await.unwrap();
+
+        s2.write_all(b"second").await.unwrap();
+        s1.write_all(b"first").await.unwrap();
+        let mut buf = [0u8; 6];
+        c2.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"second");
+        let mut buf = [0u8; 5];
+        c1.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"first");
+
+        c1.write_all(b"reply").await.unwrap();
+        s1.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"reply");
+
+        // 閉じると相手は EOF を受け取る
+        drop(s1);
+        let mut rest = Vec::new();
+        c1.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_flow_control() {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let server = Session::new(a, Side::Server);
+        let mut client = Session::new(b, Side::Client);
+
+        // ウィンドウより大きなデータも、読み進めれば全部届く
+        let data: Vec<u8> = (0..INITIAL_WINDOW as usize * 3).map(|i| i as u8).collect();
+        let mut s = server.control().open().await.unwrap();
+        let mut c = client.accept().await.unwrap();
+        let expected = data.clone();
+        let writer = tokio::spawn(async move {
+            s.write_all(&data).await.unwrap();
+            s.shutdown().await.unwrap();
+        });
+        let mut received = Vec::new();
+        c.read_to_end(&mut received).await.unwrap();
+        writer.await.unwrap();
+        assert_eq!(received, expected);
+    }
+
+    #[tokio::test]
+    async fn test_session_closed() {
+        let (a, b) = tokio::io::duplex(1024);
+        let server = Session::new(a, Side::Server);
+        let control = server.control();
+        drop(Session::new(b, Side::Client));
+
+        control.closed().await;
+        assert!(control.open().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reject_incoming() {
+        let (a, b) = tokio::io::duplex(1024);
+        let mut server = Session::new(a, Side::Server);
+        server.reject_incoming();
+        let mut client = Session::new(b, Side::Client);
+
+        // 相手が開いたストリームはすぐに閉じられる
+        let mut refused = client.control().open().await.unwrap();
+        let mut rest = Vec::new();
+        refused.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+
+        // 自分から開くストリームはそのまま使える
+        let mut s = server.control().open().await.unwrap();
+        let mut c = client.accept().await.unwrap();
+        s.write_all(b"ok").await.unwrap();
+        let mut buf = [0u8; 2];
+        c.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ok");
+    }
+
+    #[tokio::test]
+    async fn test_receive_window_exceeded() {
+        let (a, mut peer) = tokio::io::duplex(1024 * 1024);
+        let client = Session::new(a, Side::Client);
+        let control = client.control();
+
+        // WINDOW を待たずにウィンドウの2倍を送りつけるとセッションを閉じる
+        Frame::new(2, OPEN, Vec::new())
+            .write_to(&mut peer)
+            .await
+            .unwrap();
+        let chunk = vec![0u8; MAX_DATA_PAYLOAD];
+        for _ in 0..(INITIAL_WINDOW as usize * 2 / MAX_DATA_PAYLOAD) {
+            if Frame::new(2, DATA, chunk.clone())
+                .write_to(&mut peer)
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+        tokio::time::timeout(std::time::Duration::from_secs(5), control.closed())
+            .await
+            .unwrap();
+    }
+}
@@ -1,34 +1,75 @@
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Socket, Type};
 use std::collections::HashSet;
-use std::ops::Range;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, UdpSocket};
 use tokio::sync::RwLock;
 
+/// 割り当て可能なポートと待ち受けるIPアドレス（設定の再読み込みで変わる）
+struct PortSet {
+    /// 割り当て可能なポート（昇順）
+    ports: Vec<u16>,
+    /// 訪問者用ポートを開くIPアドレス
+    bind_ip: IpAddr,
+}
+
 /// ポート割り当て管理
 pub struct PortAllocator {
-    range: Range<u16>,
+    port_set: RwLock<PortSet>,
     allocated: Arc<RwLock<HashSet<u16>>>,
+    /// 名前付きトンネル用に予約されたポート
+    /// `allocate` は他に空きがない場合にだけ使う
+    reserved: Arc<RwLock<HashSet<u16>>>,
+    /// 管理 API で割り当てないよう押さえたポート（`allocated` にも入っている）
+    held: Arc<RwLock<HashSet<u16>>>,
+}
+
+/// ポートの使用状況
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortUsage {
+    /// 割り当て可能なポート数
+    pub total: usize,
+    /// 割り当て済みのポート（押さえたポートを含む、昇順）
+    pub allocated: Vec<u16>,
+    /// 名前付きトンネル用に予約されたポート（昇順）
+    pub reserved: Vec<u16>,
+    /// 管理 API で押さえたポート（昇順）
+    pub held: Vec<u16>,
 }
 
 impl PortAllocator {
     /// 新しいポートアロケーターを作成
-    pub fn new(range: Range<u16>) -> Self {
+    pub fn new(ports: Vec<u16>, bind_ip: IpAddr) -> Self {
         Self {
-            range,
+            port_set: RwLock::new(PortSet { ports, bind_ip }),
             allocated: Arc::new(RwLock::new(HashSet::new())),
+            reserved: Arc::new(RwLock::new(HashSet::new())),
+            held: Arc::new(RwLock::new(HashSet::new())),
         }
     }
 
+    /// 割り当て可能なポートと待ち受けるIPアドレスを変更
+    /// 割り当て済みのポートは解放されるまでそのまま使われる
+    pub async fn update(&self, ports: Vec<u16>, bind_ip: IpAddr) {
+        *self.port_set.write().await = PortSet { ports, bind_ip };
+    }
+
     /// 利用可能なポートを割り当て
     pub async fn allocate(&self) -> Result<u16> {
+        let port_set = self.port_set.read().await;
         let mut allocated = self.allocated.write().await;
+        let reserved = self.reserved.read().await;
 
-        // 範囲内で使用されていないポートを順番に探す
-        for port in self.range.clone() {
+        // 範囲内で使用されていないポートを順番に探す（予約済みのポートは後回し）
+        let unreserved = port_set.ports.iter().filter(|port| !reserved.contains(port));
+        let reserved_ports = port_set.ports.iter().filter(|port| reserved.contains(port));
+        for &port in unreserved.chain(reserved_ports) {
             if !allocated.contains(&port) {
                 // 実際にバインド可能か確認
-                if self.is_port_available(port).await {
+                if is_available(port_set.bind_ip, port) {
                     allocated.insert(port);
                     return Ok(port);
                 }
@@ -36,22 +77,88 @@ impl PortAllocator {
         }
 
         anyhow::bail!(
-            "No available ports in range {}-{}",
-            self.range.start,
-            self.range.end
+            "No available ports ({} ports configured)",
+            port_set.ports.len()
         )
     }
 
+    /// 指定したポートを優先して割り当て
+    /// 範囲外・使用中の場合は通常どおり空いているポートを割り当てる
+    pub async fn allocate_preferred(&self, preferred: u16) -> Result<u16> {
+        {
+            let port_set = self.port_set.read().await;
+            let mut allocated = self.allocated.write().await;
+            if port_set.ports.binary_search(&preferred).is_ok()
+                && !allocated.contains(&preferred)
+                && is_available(port_set.bind_ip, preferred)
+            {
+                allocated.insert(preferred);
+                return Ok(preferred);
+            }
+        }
+
+        self.allocate().await
+    }
+
+    /// 割り当てたポートで訪問者用のリスナーを開く
+    pub async fn bind(&self, port: u16) -> io::Result<TcpListener> {
+        let bind_ip = self.port_set.read().await.bind_ip;
+        bind_listener(bind_ip, port)
+    }
+
+    /// 割り当てたポートで UDP の訪問者用ソケットを開く
+    pub async fn bind_udp(&self, port: u16) -> io::Result<UdpSocket> {
+        let bind_ip = self.port_set.read().await.bind_ip;
+        bind_udp_socket(bind_ip, port)
+    }
+
     /// ポートを解放
     pub async fn release(&self, port: u16) {
         self.allocated.write().await.remove(&port);
     }
 
-    /// ポートが実際にバインド可能か確認
-    async fn is_port_available(&self, port: u16) -> bool {
-        TcpListener::bind(format!("0.0.0.0:{}", port))
-            .await
-            .is_ok()
+    /// 名前付きトンネル用にポートを予約
+    pub async fn reserve(&self, port: u16) {
+        self.reserved.write().await.insert(port);
+    }
+
+    /// ポートの予約を解除
+    pub async fn unreserve(&self, port: u16) {
+        self.reserved.write().await.remove(&port);
+    }
+
+    /// ポートを割り当てないよう押さえる
+    /// 範囲外・割り当て済みのポートは押さえられない
+    pub async fn hold(&self, port: u16) -> Result<()> {
+        let port_set = self.port_set.read().await;
+        let mut allocated = self.allocated.write().await;
+        if port_set.ports.binary_search(&port).is_err() {
+            anyhow::bail!("Port {} is not in the port range", port);
+        }
+        if !allocated.insert(port) {
+            anyhow::bail!("Port {} is in use", port);
+        }
+        self.held.write().await.insert(port);
+        Ok(())
+    }
+
+    /// 押さえたポートを割り当てられるよう戻す。押さえていなければ false
+    pub async fn unhold(&self, port: u16) -> bool {
+        if !self.held.write().await.remove(&port) {
+            return false;
+        }
+        self.allocated.write().await.remove(&port);
+        true
+    }
+
+    /// ポートの使用状況を取得
+    pub async fn usage(&self) -> PortUsage {
+        PortUsage {
+            total: self.port_set.read().await.ports.len(),
+            allocated: sorted(&self.allocated.read().await),
+            reserved: sorted(&self.reserved.read().await),
+            held: sorted(&self.held.read().await),
+        }
     }
 
     /// 割り当て済みポート数を取得（デバッグ用）
@@ -61,21 +168,65 @@ impl PortAllocator {
     }
 }
 
+/// ポートの集合を昇順に並べる
+fn sorted(ports: &HashSet<u16>) -> Vec<u16> {
+    let mut ports: Vec<u16> = ports.iter().copied().collect();
+    ports.sort_unstable();
+    ports
+}
+
+/// 訪問者用のリスナーを開く
+/// `::` の場合はIPv4射影アドレスも受け付けるデュアルスタックにする
+fn bind_listener(ip: IpAddr, port: u16) -> io::Result<TcpListener> {
+    let addr = SocketAddr::new(ip, port);
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if ip.is_ipv6() && ip.is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// 訪問者用の UDP ソケットを開く
+fn bind_udp_socket(ip: IpAddr, port: u16) -> io::Result<UdpSocket> {
+    let addr = SocketAddr::new(ip, port);
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+    if ip.is_ipv6() && ip.is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// TCP・UDP のどちらのトンネルにも使えるよう、両方でバインドできるか確認
+fn is_available(ip: IpAddr, port: u16) -> bool {
+    bind_listener(ip, port).is_ok() && bind_udp_socket(ip, port).is_ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::Ipv4Addr;
+
+    fn new_allocator(ports: std::ops::Range<u16>) -> PortAllocator {
+        PortAllocator::new(ports.collect(), IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    }
 
     #[tokio::test]
     async fn test_allocate_and_release() {
-        let allocator = PortAllocator::new(35100..35110);
+        let allocator = new_allocator(35100..35110);
 
         // ポートを割り当て
         let port1 = allocator.allocate().await.unwrap();
-        assert!(port1 >= 35100 && port1 < 35110);
+        assert!((35100..35110).contains(&port1));
 
         // 別のポートを割り当て
         let port2 = allocator.allocate().await.unwrap();
-        assert!(port2 >= 35100 && port2 < 35110);
+        assert!((35100..35110).contains(&port2));
         assert_ne!(port1, port2);
 
         // ポートを解放
@@ -83,16 +234,16 @@ mod tests {
 
         // 再度割り当て可能
         let port3 = allocator.allocate().await.unwrap();
-        assert!(port3 >= 35100 && port3 < 35110);
+        assert!((35100..35110).contains(&port3));
     }
 
     #[tokio::test]
     async fn test_port_exhaustion() {
         // 小さな範囲でテスト
-        let allocator = PortAllocator::new(35100..35102);
+        let allocator = new_allocator(35100..35102);
 
         let port1 = allocator.allocate().await.unwrap();
-        let port2 = allocator.allocate().await.unwrap();
+        let _port2 = allocator.allocate().await.unwrap();
 
         // 3つ目はエラー（範囲は2つのみ）
         let result = allocator.allocate().await;
@@ -103,4 +254,76 @@ mod tests {
         let port3 = allocator.allocate().await;
         assert!(port3.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_allocate_preferred() {
+        let allocator = new_allocator(35110..35120);
+
+        // 空いていれば希望どおり
+        let port = allocator.allocate_preferred(35115).await.unwrap();
+        assert_eq!(port, 35115);
+
+        // 使用中なら別のポート
+        let port = allocator.allocate_preferred(35115).await.unwrap();
+        assert_ne!(port, 35115);
+        assert!((35110..35120).contains(&port));
+
+        // 範囲外なら範囲内のポート
+        let port = allocator.allocate_preferred(40000).await.unwrap();
+        assert!((35110..35120).contains(&port));
+    }
+
+    #[tokio::test]
+    async fn test_reserved_ports_allocated_last() {
+        let allocator = new_allocator(35120..35123);
+        allocator.reserve(35120).await;
+
+        // 予約済みのポートは他に空きがあれば使わない
+        assert_eq!(allocator.allocate().await.unwrap(), 35121);
+        assert_eq!(allocator.allocate().await.unwrap(), 35122);
+        assert_eq!(allocator.allocate().await.unwrap(), 35120);
+
+        // 希望すれば予約済みでも割り当てる
+        allocator.release(35120).await;
+        assert_eq!(allocator.allocate_preferred(35120).await.unwrap(), 35120);
+    }
+
+    #[tokio::test]
+    async fn test_hold_ports() {
+        let allocator = new_allocator(35140..35142);
+
+        // 押さえたポートは割り当てない
+        allocator.hold(35140).await.unwrap();
+        assert_eq!(allocator.allocate().await.unwrap(), 35141);
+        assert!(allocator.allocate().await.is_err());
+
+        // 範囲外・使用中のポートは押さえられない
+        assert!(allocator.hold(40000).await.is_err());
+        assert!(allocator.hold(35141).await.is_err());
+
+        let usage = allocator.usage().await;
+        assert_eq!(usage.total, 2);
+        assert_eq!(usage.allocated, vec![35140, 35141]);
+        assert_eq!(usage.held, vec![35140]);
+
+        // 戻せば割り当てられる。押さえていないポートは戻せない
+        assert!(allocator.unhold(35140).await);
+        assert!(!allocator.unhold(35141).await);
+        assert_eq!(allocator.allocate().await.unwrap(), 35140);
+    }
+
+    #[tokio::test]
+    async fn test_update_ports() {
+        let allocator = new_allocator(35130..35132);
+        let port = allocator.allocate().await.unwrap();
+        assert_eq!(port, 35130);
+
+        // 変更後は新しい範囲から割り当て、範囲外になったポートは希望しても使わない
+        allocator
+            .update(vec![35135], IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+            .await;
+        allocator.release(port).await;
+        assert_eq!(allocator.allocate_preferred(35130).await.unwrap(), 35135);
+        assert!(allocator.allocate().await.is_err());
+    }
 }
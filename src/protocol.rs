@@ -1,23 +1,185 @@
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 
-/// プロトコルメッセージ（4種類のみ）
+use crate::config::ServiceProtocol;
+
+/// プロトコルメッセージ
 /// JSON形式でシリアライズされ、言語非依存
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 pub enum Message {
+    /// 双方向: コントロールチャネルの最初のメッセージ（`TunnelRequest` の前）
+    /// クライアントが送り、サーバーは同じバージョンを話せれば自分の `Hello` を返す
+    /// `capabilities` は対応している機能の名前。知らない名前は無視する
+    Hello {
+        version: u32,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
+
     /// クライアント → サーバー: トンネル作成リクエスト
-    TunnelRequest { local_port: u16 },
+    /// `remote_port` は希望するポート（再接続時は前回割り当てられたポート）
+    /// `name` はトンネル名。サーバーは名前ごとに前回のポートを覚えている
+    /// `protocol` は訪問者用ポートのプロトコル（省略時は tcp）
+    /// `multiplex` はデータチャネルを1本の接続に多重化したいかどうか
+    /// `pool_size` は前もって張っておきたい待機中のデータチャネルの数
+    TunnelRequest {
+        local_port: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        remote_port: Option<u16>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        #[serde(default, skip_serializing_if = "ServiceProtocol::is_tcp")]
+        protocol: ServiceProtocol,
+        #[serde(default, skip_serializing_if = "is_false")]
+        multiplex: bool,
+        #[serde(default, skip_serializing_if = "is_zero")]
+        pool_size: u16,
+    },
+
+    /// サーバー → クライアント: 認証チャレンジ（認証が有効な場合のみ）
+    AuthChallenge { nonce: String },
+
+    /// クライアント → サーバー: チャレンジへの応答
+    /// `digest` は HMAC-SHA256(token, nonce) の16進文字列
+    AuthResponse { digest: String },
+
+    /// サーバー → クライアント: トンネル作成を拒否（送信後に切断される）
+    TunnelRejected { reason: String },
 
-    /// サーバー → クライアント: 割り当てたポート番号
-    TunnelResponse { assigned_port: u16 },
+    /// サーバー → クライアント: 割り当てたポート番号とセッショントークン
+    /// `multiplex` が true ならクライアントは `MuxChannelHello` で多重化用の接続を張る
+    /// `pool_size` はサーバーが受け入れる待機中のデータチャネルの数
+    TunnelResponse {
+        assigned_port: u16,
+        session: String,
+        #[serde(default, skip_serializing_if = "is_false")]
+        multiplex: bool,
+        #[serde(default, skip_serializing_if = "is_zero")]
+        pool_size: u16,
+    },
 
-    /// サーバー → クライアント: データチャネルを作成して
-    CreateDataChannel,
+    /// クライアント → サーバー: データチャネルの最初のメッセージ
+    /// `session` でどのコントロールチャネルに属するかを、
+    /// `visitor_id` でどの訪問者のためのチャネルかを示す
+    /// `POOLED_VISITOR_ID` なら訪問者を待つ待機中のデータチャネル
+    DataChannelHello {
+        session: String,
+        nonce: String,
+        visitor_id: u64,
+    },
+
+    /// サーバー → クライアント: 指定した訪問者用のデータチャネルを作成して
+    /// `POOLED_VISITOR_ID` なら待機中のデータチャネルを1本補充して
+    /// 待機中のデータチャネル上で送られた場合は、そのチャネルを訪問者に割り当てたことを示す
+    CreateDataChannel { visitor_id: u64 },
+
+    /// クライアント → サーバー: 多重化用の接続の最初のメッセージ
+    /// 以降はこの接続の上で訪問者ごとのストリームを多重化する
+    MuxChannelHello { session: String },
+
+    /// サーバー → クライアント: トンネルを作れない・続けられない理由。送った後に切断する
+    /// `TunnelRejected` の代わりに、Hello で `error` を伝えてきたクライアントにだけ送る
+    /// `retryable` が false ならクライアントは再接続しない
+    Error {
+        code: ErrorCode,
+        message: String,
+        retryable: bool,
+    },
 
     /// 双方向: ハートビート
     Heartbeat,
+
+    /// 知らない種類のメッセージ（新しいバージョンの相手が送ったもの）。受け取った側は無視する
+    #[serde(other)]
+    Unknown,
+}
+
+/// このクレートが話すプロトコルのバージョン
+/// 互換性のない変更をしたときだけ上げる。互換性のある追加は `CAPABILITIES` で伝える
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// このクレートが対応している機能（`Hello` の `capabilities`）
+pub const CAPABILITIES: &[&str] = &["auth", "udp", "multiplex", "pool", "error"];
+
+/// `Hello` で送る対応機能の一覧
+pub fn capabilities() -> Vec<String> {
+    CAPABILITIES.iter().map(|name| name.to_string()).collect()
+}
+
+/// `Error` メッセージで伝えるエラーの種類
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// サーバーがクライアントのプロトコルバージョンを話せない
+    UnsupportedVersion,
+    /// 認証に失敗した（トークンがない・間違っている）
+    AuthFailed,
+    /// トンネル名が他のトークンに予約されている
+    NameReserved,
+    /// トークンごとの同時トンネル数の上限に達した
+    TunnelLimit,
+    /// 割り当てられるポートが残っていない
+    PortsExhausted,
+    /// 訪問者用のポートを開けなかった
+    BindFailed,
+    /// 同じ名前の新しいトンネルに置き換えられた
+    Replaced,
+    /// 使っていたトークンが設定から削除された
+    TokenRevoked,
+    /// 管理 API で切断された
+    Disconnected,
+    /// サーバーが停止する
+    ShuttingDown,
+    /// このバージョンが知らない種類
+    #[serde(other)]
+    Other,
+}
+
+impl ErrorCode {
+    /// 時間をおいて再接続すれば成功する見込みがあるか
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            ErrorCode::TunnelLimit
+                | ErrorCode::PortsExhausted
+                | ErrorCode::BindFailed
+                | ErrorCode::ShuttingDown
+        )
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            ErrorCode::UnsupportedVersion => "unsupported protocol version",
+            ErrorCode::AuthFailed => "authentication failed",
+            ErrorCode::NameReserved => "name reserved",
+            ErrorCode::TunnelLimit => "tunnel limit reached",
+            ErrorCode::PortsExhausted => "port range exhausted",
+            ErrorCode::BindFailed => "failed to open the visitor port",
+            ErrorCode::Replaced => "replaced by a newer tunnel",
+            ErrorCode::TokenRevoked => "token revoked",
+            ErrorCode::Disconnected => "disconnected by the administrator",
+            ErrorCode::ShuttingDown => "server shutting down",
+            ErrorCode::Other => "error",
+        };
+        f.write_str(description)
+    }
+}
+
+/// 待機中のデータチャネルを表す訪問者ID（訪問者には 1 から割り当てる）
+pub const POOLED_VISITOR_ID: u64 = 0;
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn is_zero(value: &u16) -> bool {
+    *value == 0
 }
 
 impl Message {
@@ -77,6 +239,44 @@ impl Message {
     }
 }
 
+/// バックグラウンドでメッセージを受信し続けるリーダー
+/// `Message::read_from` はキャンセル安全ではないため、`select!` の中ではこちらを使う
+pub struct MessageReader {
+    rx: mpsc::Receiver<Result<Message>>,
+    handle: JoinHandle<()>,
+}
+
+impl MessageReader {
+    /// 受信タスクを起動
+    pub fn spawn<R: AsyncRead + Unpin + Send + 'static>(mut reader: R) -> Self {
+        let (tx, rx) = mpsc::channel(32);
+        let handle = tokio::spawn(async move {
+            loop {
+                let result = Message::read_from(&mut reader).await;
+                let failed = result.is_err();
+                if tx.send(result).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Self { rx, handle }
+    }
+
+    /// 次のメッセージを受信（キャンセル安全）
+    pub async fn recv(&mut self) -> Result<Message> {
+        match self.rx.recv().await {
+            Some(result) => result,
+            None => Err(anyhow::anyhow!("Connection closed")),
+        }
+    }
+}
+
+impl Drop for MessageReader {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -84,9 +284,69 @@ mod tests {
     #[tokio::test]
     async fn test_message_roundtrip() {
         let messages = vec![
-            Message::TunnelRequest { local_port: 8080 },
-            Message::TunnelResponse { assigned_port: 35100 },
-            Message::CreateDataChannel,
+            Message::Hello {
+                version: PROTOCOL_VERSION,
+                capabilities: capabilities(),
+            },
+            Message::TunnelRequest {
+                local_port: 8080,
+                remote_port: None,
+                name: None,
+                protocol: ServiceProtocol::Tcp,
+                multiplex: false,
+                pool_size: 0,
+            },
+            Message::TunnelRequest {
+                local_port: 8080,
+                remote_port: Some(35123),
+                name: Some("web".to_string()),
+                protocol: ServiceProtocol::Tcp,
+                multiplex: true,
+                pool_size: 0,
+            },
+            Message::TunnelRequest {
+                local_port: 53,
+                remote_port: None,
+                name: Some("dns".to_string()),
+                protocol: ServiceProtocol::Udp,
+                multiplex: false,
+                pool_size: 4,
+            },
+            Message::AuthChallenge {
+                nonce: "00ff".to_string(),
+            },
+            Message::AuthResponse {
+                digest: "abcd".to_string(),
+            },
+            Message::TunnelRejected {
+                reason: "authentication failed".to_string(),
+            },
+            Message::TunnelResponse {
+                assigned_port: 35100,
+                session: "0123456789abcdef".to_string(),
+                multiplex: false,
+                pool_size: 4,
+            },
+            Message::TunnelResponse {
+                assigned_port: 35101,
+                session: "0123456789abcdef".to_string(),
+                multiplex: true,
+                pool_size: 0,
+            },
+            Message::DataChannelHello {
+                session: "0123456789abcdef".to_string(),
+                nonce: "cafebabe".to_string(),
+                visitor_id: 7,
+            },
+            Message::CreateDataChannel { visitor_id: 7 },
+            Message::MuxChannelHello {
+                session: "0123456789abcdef".to_string(),
+            },
+            Message::Error {
+                code: ErrorCode::PortsExhausted,
+                message: "port range exhausted".to_string(),
+                retryable: true,
+            },
             Message::Heartbeat,
         ];
 
@@ -99,13 +359,94 @@ mod tests {
 
             // メッセージが正しくエンコード/デコードされることを確認
             match (msg, decoded) {
-                (Message::TunnelRequest { local_port: p1 }, Message::TunnelRequest { local_port: p2 }) => {
+                (
+                    Message::Hello { version: v1, capabilities: c1 },
+                    Message::Hello { version: v2, capabilities: c2 },
+                ) => {
+                    assert_eq!(v1, v2);
+                    assert_eq!(c1, c2);
+                }
+                (
+                    Message::TunnelRequest {
+                        local_port: p1,
+                        remote_port: r1,
+                        name: n1,
+                        protocol: t1,
+                        multiplex: m1,
+                        pool_size: s1,
+                    },
+                    Message::TunnelRequest {
+                        local_port: p2,
+                        remote_port: r2,
+                        name: n2,
+                        protocol: t2,
+                        multiplex: m2,
+                        pool_size: s2,
+                    },
+                ) => {
                     assert_eq!(p1, p2);
+                    assert_eq!(r1, r2);
+                    assert_eq!(n1, n2);
+                    assert_eq!(t1, t2);
+                    assert_eq!(m1, m2);
+                    assert_eq!(s1, s2);
+                }
+                (Message::AuthChallenge { nonce: n1 }, Message::AuthChallenge { nonce: n2 }) => {
+                    assert_eq!(n1, n2);
+                }
+                (Message::AuthResponse { digest: d1 }, Message::AuthResponse { digest: d2 }) => {
+                    assert_eq!(d1, d2);
+                }
+                (Message::TunnelRejected { reason: r1 }, Message::TunnelRejected { reason: r2 }) => {
+                    assert_eq!(r1, r2);
                 }
-                (Message::TunnelResponse { assigned_port: p1 }, Message::TunnelResponse { assigned_port: p2 }) => {
+                (
+                    Message::TunnelResponse {
+                        assigned_port: p1,
+                        session: s1,
+                        multiplex: m1,
+                        pool_size: z1,
+                    },
+                    Message::TunnelResponse {
+                        assigned_port: p2,
+                        session: s2,
+                        multiplex: m2,
+                        pool_size: z2,
+                    },
+                ) => {
                     assert_eq!(p1, p2);
+                    assert_eq!(s1, s2);
+                    assert_eq!(m1, m2);
+                    assert_eq!(z1, z2);
+                }
+                (
+                    Message::DataChannelHello { session: s1, nonce: n1, visitor_id: v1 },
+                    Message::DataChannelHello { session: s2, nonce: n2, visitor_id: v2 },
+                ) => {
+                    assert_eq!(s1, s2);
+                    assert_eq!(n1, n2);
+                    assert_eq!(v1, v2);
+                }
+                (
+                    Message::CreateDataChannel { visitor_id: v1 },
+                    Message::CreateDataChannel { visitor_id: v2 },
+                ) => {
+                    assert_eq!(v1, v2);
+                }
+                (
+                    Message::MuxChannelHello { session: s1 },
+                    Message::MuxChannelHello { session: s2 },
+                ) => {
+                    assert_eq!(s1, s2);
+                }
+                (
+                    Message::Error { code: c1, message: m1, retryable: r1 },
+                    Message::Error { code: c2, message: m2, retryable: r2 },
+                ) => {
+                    assert_eq!(c1, c2);
+                    assert_eq!(m1, m2);
+                    assert_eq!(r1, r2);
                 }
-                (Message::CreateDataChannel, Message::CreateDataChannel) => {}
                 (Message::Heartbeat, Message::Heartbeat) => {}
                 _ => panic!("Message mismatch"),
             }
@@ -115,7 +456,14 @@ mod tests {
     #[tokio::test]
     async fn test_json_format() {
         // JSONフォーマットが正しいか確認
-        let msg = Message::TunnelRequest { local_port: 8080 };
+        let msg = Message::TunnelRequest {
+            local_port: 8080,
+            remote_port: None,
+            name: None,
+            protocol: ServiceProtocol::Tcp,
+            multiplex: false,
+            pool_size: 0,
+        };
         let mut buf = Vec::new();
         msg.write_to(&mut buf).await.unwrap();
 
@@ -127,5 +475,111 @@ mod tests {
         let parsed: serde_json::Value = serde_json::from_str(&json_str).unwrap();
         assert_eq!(parsed["type"], "TunnelRequest");
         assert_eq!(parsed["local_port"], 8080);
+        // 省略可能なフィールドは出力しない（古い実装との互換性）
+        assert!(parsed.get("remote_port").is_none());
+        assert!(parsed.get("name").is_none());
+        assert!(parsed.get("protocol").is_none());
+        assert!(parsed.get("multiplex").is_none());
+        assert!(parsed.get("pool_size").is_none());
+    }
+
+    #[test]
+    fn test_optional_field_defaults() {
+        // remote_port・name・protocol・multiplex・pool_size を含まない古いクライアントのリクエストも受け付ける
+        let msg: Message =
+            serde_json::from_str(r#"{"type":"TunnelRequest","local_port":22}"#).unwrap();
+        match msg {
+            Message::TunnelRequest {
+                local_port,
+                remote_port,
+                name,
+                protocol,
+                multiplex,
+                pool_size,
+            } => {
+                assert_eq!(local_port, 22);
+                assert_eq!(remote_port, None);
+                assert_eq!(name, None);
+                assert_eq!(protocol, ServiceProtocol::Tcp);
+                assert!(!multiplex);
+                assert_eq!(pool_size, 0);
+            }
+            _ => panic!("Message mismatch"),
+        }
+
+        // multiplex・pool_size を返さない古いサーバーは多重化・待機中のデータチャネルに対応していない
+        let msg: Message = serde_json::from_str(
+            r#"{"type":"TunnelResponse","assigned_port":35100,"session":"00ff"}"#,
+        )
+        .unwrap();
+        match msg {
+            Message::TunnelResponse {
+                multiplex,
+                pool_size,
+                ..
+            } => {
+                assert!(!multiplex);
+                assert_eq!(pool_size, 0);
+            }
+            _ => panic!("Message mismatch"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unknown_message() {
+        // 新しいバージョンの相手が送る知らないメッセージでも接続を閉じない
+        let json = r#"{"type":"SomethingNew","field":1}"#;
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(json.len() as u32).to_le_bytes());
+        buf.extend_from_slice(json.as_bytes());
+        Message::Heartbeat.write_to(&mut buf).await.unwrap();
+
+        let mut cursor = std::io::Cursor::new(buf);
+        assert!(matches!(
+            Message::read_from(&mut cursor).await.unwrap(),
+            Message::Unknown
+        ));
+        assert!(matches!(
+            Message::read_from(&mut cursor).await.unwrap(),
+            Message::Heartbeat
+        ));
+
+        // capabilities を省略した Hello も受け付ける
+        let msg: Message = serde_json::from_str(r#"{"type":"Hello","version":1}"#).unwrap();
+        match msg {
+            Message::Hello {
+                version,
+                capabilities,
+            } => {
+                assert_eq!(version, 1);
+                assert!(capabilities.is_empty());
+            }
+            _ => panic!("Message mismatch"),
+        }
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let msg: Message = serde_json::from_str(
+            r#"{"type":"Error","code":"auth_failed","message":"authentication failed","retryable":false}"#,
+        )
+        .unwrap();
+        match msg {
+            Message::Error { code, retryable, .. } => {
+                assert_eq!(code, ErrorCode::AuthFailed);
+                assert!(!retryable);
+            }
+            _ => panic!("Message mismatch"),
+        }
+
+        // 新しいサーバーの知らない種類は Other になる
+        let code: ErrorCode = serde_json::from_str(r#""something_new""#).unwrap();
+        assert_eq!(code, ErrorCode::Other);
+
+        assert!(ErrorCode::PortsExhausted.is_retryable());
+        assert!(!ErrorCode::AuthFailed.is_retryable());
+        assert!(!ErrorCode::Disconnected.is_retryable());
+        assert!(ErrorCode::ShuttingDown.is_retryable());
+        assert_eq!(ErrorCode::PortsExhausted.to_string(), "port range exhausted");
     }
 }
@@ -1,49 +1,339 @@
 use anyhow::{Context, Result};
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{broadcast, mpsc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Mutex, RwLock};
 use tokio::time::timeout;
 use tracing::{debug, error, info, warn};
 
+use crate::admin::{self, AdminCommand, AdminListener, AdminReply, AdminRequest, TunnelSummary};
+use crate::auth;
+use crate::config::{ServerConfig, ServiceProtocol, TransportType};
+use crate::error::Error;
+use crate::mux;
 use crate::port_allocator::PortAllocator;
-use crate::protocol::Message;
+use crate::stats::{CountedStream, TunnelStats};
+use crate::protocol::{
+    self, ErrorCode, Message, MessageReader, POOLED_VISITOR_ID, PROTOCOL_VERSION,
+};
+use crate::server_handle::ServerEvent;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use crate::transport::TlsTransport;
+#[cfg(feature = "noise")]
+use crate::transport::NoiseTransport;
+#[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
+use crate::transport::WebsocketTransport;
+use crate::transport::{TcpTransport, Transport};
+use crate::udp;
 
-const PORT_RANGE_START: u16 = 35100;
-const PORT_RANGE_END: u16 = 35200;
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(60);
+const DATA_CHANNEL_TIMEOUT: Duration = Duration::from_secs(10);
+/// トランスポートのハンドシェイク・最初のメッセージを待つ時間
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// クライアントごとに受け入れる待機中のデータチャネルの上限
+const MAX_POOL_SIZE: u16 = 16;
+/// コントロールチャネルごとに覚えておくデータチャネルのnonceの数
+const NONCE_HISTORY: usize = 1024;
+/// シャットダウン時にトンネルが閉じ終わるのを待つ時間
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// 購読者が読み遅れても残しておくイベントの数
+const EVENT_CAPACITY: usize = 64;
+/// 使われていない名前付きトンネルのポートを予約しておく時間
+const NAME_BINDING_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// 期限切れの名前を探す間隔
+const NAME_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+/// トークンごとに覚えておく名前の数（認証なしの場合はすべてのクライアントで共有する）
+const MAX_NAMES_PER_TOKEN: usize = 32;
+
+/// セッショントークン → クライアント情報
+type ClientMap<T> = Arc<RwLock<HashMap<String, ClientInfo<T>>>>;
+
+/// 訪問者ID → データチャネルの受け渡し先
+/// データチャネルを待っている訪問者だけが登録される
+type PendingVisitors<T> = Arc<Mutex<HashMap<u64, oneshot::Sender<<T as Transport>::Stream>>>>;
+
+/// トンネル名 → 最後に割り当てたポート
+/// クライアントが再起動しても同じ名前なら同じポートを返す
+type NameBindings = Arc<Mutex<HashMap<String, NameBinding>>>;
+
+/// 名前付きトンネルに割り当てたポートの記録
+/// ポートの予約はベストエフォートで、他に空きがない場合や `remote_port` で希望された場合は
+/// 別のクライアントにも割り当てられる
+struct NameBinding {
+    port: u16,
+    /// 名前を使ったトークン（認証なしの場合は None）
+    token: Option<String>,
+    /// 名前を使っているトンネルのセッショントークン（閉じていれば None）
+    session: Option<String>,
+    /// 最後に使われていた時刻
+    last_used: Instant,
+}
+
+/// 訪問者の送信元アドレス → UDP フローへの送信側
+type UdpFlows = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>>;
+
+/// トンネルを閉じる要求。受け取った側はポートを解放してから `done` に応答する
+struct CloseRequest {
+    code: ErrorCode,
+    reason: String,
+    done: oneshot::Sender<()>,
+}
 
 /// クライアント情報
-struct ClientInfo {
+struct ClientInfo<T: Transport> {
+    /// コントロールチャネルの接続元
+    addr: SocketAddr,
     assigned_port: u16,
-    data_channel_tx: mpsc::Sender<TcpStream>,
+    local_port: u16,
+    protocol: ServiceProtocol,
+    name: Option<String>,
+    /// 認証に使われたトークン（認証なしの場合は None）
+    token: Option<String>,
+    pending_visitors: PendingVisitors<T>,
+    /// 受け取ったデータチャネルのnonce（同じ `DataChannelHello` が重複して届いたら拒否する）
+    nonces: auth::NonceHistory,
     control_channel_tx: mpsc::Sender<Message>,
+    /// 置き換え・トークンの失効などでサーバー側から閉じるときに使う
+    close_tx: Option<oneshot::Sender<CloseRequest>>,
+    /// 多重化用の接続の受け渡し先（多重化しない場合・受け取り済みなら None）
+    mux_tx: Option<oneshot::Sender<<T as Transport>::Stream>>,
+    /// 待機中のデータチャネルの置き場（待機中のデータチャネルを使わない場合は None）
+    data_channel_tx: Option<mpsc::Sender<<T as Transport>::Stream>>,
+    connected_since: SystemTime,
+    stats: Arc<TunnelStats>,
 }
 
-/// サーバーを実行
-pub async fn run_server(bind_addr: String, mut shutdown_rx: broadcast::Receiver<()>) -> Result<()> {
-    let listener = TcpListener::bind(&bind_addr)
+impl<T: Transport> ClientInfo<T> {
+    /// トンネルを閉じるよう要求する。閉じ終わると返り値の受信側に通知される
+    fn close(&mut self, code: ErrorCode, reason: &str) -> Option<oneshot::Receiver<()>> {
+        let close_tx = self.close_tx.take()?;
+        let (done, done_rx) = oneshot::channel();
+        close_tx
+            .send(CloseRequest {
+                code,
+                reason: reason.to_string(),
+                done,
+            })
+            .ok()?;
+        Some(done_rx)
+    }
+
+    /// 管理 API で見せる情報
+    fn summary(&self) -> TunnelSummary {
+        TunnelSummary {
+            client_addr: self.addr,
+            name: self.name.clone(),
+            remote_port: self.assigned_port,
+            local_port: self.local_port,
+            protocol: self.protocol,
+            connected_since: self
+                .connected_since
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+            active_visitors: self.stats.active_visitors(),
+            bytes_in: self.stats.bytes_in(),
+            bytes_out: self.stats.bytes_out(),
+        }
+    }
+}
+
+/// サーバーを埋め込んだ側（`ServerHandle`）とのやり取り
+pub(crate) struct ServerHooks {
+    /// 待ち受けを始めたら実際のアドレスを通知する
+    pub ready: Option<oneshot::Sender<SocketAddr>>,
+    /// トンネルの開閉・拒否を通知する（購読者がいなければ捨てる）
+    pub events: broadcast::Sender<ServerEvent>,
+    /// 管理 API と同じ操作を受け付ける。管理 API もこの送信側を使う
+    pub requests: mpsc::Sender<AdminRequest>,
+    pub requests_rx: mpsc::Receiver<AdminRequest>,
+}
+
+impl ServerHooks {
+    pub fn new() -> Self {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let (requests, requests_rx) = mpsc::channel(16);
+        Self {
+            ready: None,
+            events,
+            requests,
+            requests_rx,
+        }
+    }
+}
+
+/// サーバーを実行（認証なし）
+pub async fn run_server(
+    bind_addr: String,
+    shutdown_rx: broadcast::Receiver<()>,
+) -> Result<(), Error> {
+    run_server_with_config(ServerConfig::new(bind_addr), shutdown_rx).await
+}
+
+/// 設定を指定してサーバーを実行
+pub async fn run_server_with_config(
+    config: ServerConfig,
+    shutdown_rx: broadcast::Receiver<()>,
+) -> Result<(), Error> {
+    config.validate().map_err(Error::config)?;
+    let (_config_tx, config_rx) = watch::channel(Arc::new(config));
+    Ok(run_server_with_updates(config_rx, shutdown_rx, ServerHooks::new()).await?)
+}
+
+/// 設定ファイルを読み込んでサーバーを実行
+/// `hot-reload` 機能が有効なら、ファイルの変更を検知して設定を再読み込みする
+pub async fn run_server_with_config_file(
+    path: impl AsRef<Path>,
+    shutdown_rx: broadcast::Receiver<()>,
+) -> Result<(), Error> {
+    let path = path.as_ref().to_path_buf();
+    let config = ServerConfig::from_file(&path).map_err(Error::config)?;
+    let (config_tx, config_rx) = watch::channel(Arc::new(config));
+
+    #[cfg(feature = "hot-reload")]
+    let watcher = tokio::spawn(async move {
+        if let Err(e) = crate::config_watcher::watch_config(path, config_tx).await {
+            error!("Config watcher stopped: {:#}", e);
+        }
+    });
+    #[cfg(not(feature = "hot-reload"))]
+    let _config_tx = {
+        debug!("Hot reload is not compiled in, {} is read only once", path.display());
+        config_tx
+    };
+
+    let result = run_server_with_updates(config_rx, shutdown_rx, ServerHooks::new()).await;
+    #[cfg(feature = "hot-reload")]
+    watcher.abort();
+    Ok(result?)
+}
+
+/// 設定の更新を受け取りながらサーバーを実行
+pub(crate) async fn run_server_with_updates(
+    config_rx: watch::Receiver<Arc<ServerConfig>>,
+    shutdown_rx: broadcast::Receiver<()>,
+    hooks: ServerHooks,
+) -> Result<()> {
+    let transport_type = config_rx.borrow().transport.transport_type;
+    match transport_type {
+        TransportType::Tcp => {
+            run_server_with_transport::<TcpTransport>(config_rx, shutdown_rx, hooks).await
+        }
+        TransportType::Tls => {
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            let result =
+                run_server_with_transport::<TlsTransport>(config_rx, shutdown_rx, hooks).await;
+            #[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+            let result = crate::transport::feature_not_compiled("tls");
+            result
+        }
+        TransportType::Noise => {
+            #[cfg(feature = "noise")]
+            let result =
+                run_server_with_transport::<NoiseTransport>(config_rx, shutdown_rx, hooks).await;
+            #[cfg(not(feature = "noise"))]
+            let result = crate::transport::feature_not_compiled("noise");
+            result
+        }
+        TransportType::Websocket => {
+            #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
+            let result =
+                run_server_with_transport::<WebsocketTransport>(config_rx, shutdown_rx, hooks)
+                    .await;
+            #[cfg(not(any(feature = "websocket-native-tls", feature = "websocket-rustls")))]
+            let result = crate::transport::feature_not_compiled("websocket");
+            result
+        }
+    }
+}
+
+/// 指定したトランスポートでサーバーを実行
+async fn run_server_with_transport<T: Transport>(
+    mut config_rx: watch::Receiver<Arc<ServerConfig>>,
+    mut shutdown_rx: broadcast::Receiver<()>,
+    hooks: ServerHooks,
+) -> Result<()> {
+    let ServerHooks {
+        ready,
+        events,
+        requests: admin_tx,
+        requests_rx: mut admin_rx,
+    } = hooks;
+    let mut config = config_rx.borrow_and_update().clone();
+    let transport = Arc::new(T::new(&config.transport).map_err(Error::config)?);
+    let acceptor = transport
+        .bind(&config.bind_addr)
         .await
-        .with_context(|| format!("Failed to bind to {}", bind_addr))?;
+        .map_err(Error::io)?;
+    let local_addr = transport.local_addr(&acceptor).map_err(Error::io)?;
 
-    info!("Server listening on {}", bind_addr);
-    info!("Port range: {}-{}", PORT_RANGE_START, PORT_RANGE_END);
+    info!(
+        "Server listening on {} ({})",
+        local_addr, config.transport.transport_type
+    );
+    log_port_settings(&config);
+    if config.tokens.is_empty() {
+        warn!("No tokens configured, authentication is disabled");
+    }
 
-    let port_allocator = Arc::new(PortAllocator::new(PORT_RANGE_START..PORT_RANGE_END));
-    let clients: Arc<RwLock<HashMap<SocketAddr, ClientInfo>>> = Arc::new(RwLock::new(HashMap::new()));
+    let port_allocator = Arc::new(PortAllocator::new(
+        config.visitor_ports(),
+        config.visitor_bind_ip,
+    ));
+    let clients: ClientMap<T> = Arc::new(RwLock::new(HashMap::new()));
+    let names: NameBindings = Arc::new(Mutex::new(HashMap::new()));
+    let mut name_expiry = tokio::time::interval(NAME_EXPIRY_INTERVAL);
+
+    // 管理 API・`ServerHandle` は操作をこのタスクに渡す
+    let admin_handle = match &config.admin {
+        Some(admin) => {
+            let listener = AdminListener::bind(&admin.bind_addr)
+                .await
+                .map_err(Error::io)?;
+            info!("Admin API listening on {}", admin.bind_addr);
+            Some(tokio::spawn(admin::serve(
+                listener,
+                admin.token.clone(),
+                admin_tx.clone(),
+            )))
+        }
+        None => None,
+    };
+    if let Some(ready) = ready {
+        let _ = ready.send(local_addr);
+    }
 
     loop {
         tokio::select! {
-            result = listener.accept() => {
+            result = transport.accept(&acceptor) => {
                 match result {
-                    Ok((stream, addr)) => {
+                    Ok((conn, addr)) => {
                         debug!("New connection from {}", addr);
+                        let transport = transport.clone();
+                        let config = config.clone();
                         let allocator = port_allocator.clone();
                         let clients = clients.clone();
+                        let names = names.clone();
+                        let events = events.clone();
                         tokio::spawn(async move {
-                            if let Err(e) = handle_connection(stream, addr, allocator, clients).await {
+                            // ハンドシェイクは accept ループを止めないよう接続ごとのタスクで行う
+                            // 何も送ってこない相手にタスクと fd を取られ続けないよう時間を区切る
+                            let stream = match timeout(HANDSHAKE_TIMEOUT, transport.handshake(conn)).await {
+                                Ok(Ok(stream)) => stream,
+                                Ok(Err(e)) => {
+                                    warn!("Handshake with {} failed: {:#}", addr, e);
+                                    return;
+                                }
+                                Err(_) => {
+                                    warn!("Timeout waiting for handshake with {}", addr);
+                                    return;
+                                }
+                            };
+                            if let Err(e) = handle_connection(stream, addr, config, allocator, clients, names, events).await {
                                 error!("Connection error from {}: {}", addr, e);
                             }
                         });
@@ -53,147 +343,556 @@ pub async fn run_server(bind_addr: String, mut shutdown_rx: broadcast::Receiver<
                     }
                 }
             }
-            _ = shutdown_rx.recv() => {
-                info!("Server shutdown requested");
-                return Ok(());
+            // 設定の再読み込み。確立済みのトンネルはそのまま使い続ける
+            Ok(()) = config_rx.changed() => {
+                let new_config = config_rx.borrow_and_update().clone();
+                apply_config(&config, &new_config, &port_allocator, &clients).await;
+                config = new_config;
+            }
+            // 管理 API の操作。トンネルが閉じるのを待つことがあるので別のタスクで行う
+            Some(request) = admin_rx.recv() => {
+                let clients = clients.clone();
+                let allocator = port_allocator.clone();
+                let names = names.clone();
+                tokio::spawn(async move {
+                    let reply = handle_admin_command(request.command, &clients, &allocator, &names).await;
+                    let _ = request.reply.send(reply);
+                });
+            }
+            _ = name_expiry.tick() => {
+                expire_names(&names, &port_allocator).await;
             }
+            _ = shutdown_rx.recv() => break,
+        }
+    }
+
+    // 新しい接続を受け付けるのをやめてから、開いているトンネルを閉じる
+    info!("Server shutdown requested");
+    if let Some(admin_handle) = &admin_handle {
+        admin_handle.abort();
+    }
+    drop(acceptor);
+    close_all_tunnels(&clients).await;
+    Ok(())
+}
+
+/// すべてのトンネルを閉じて、ポートが解放されるまで待つ
+/// クライアントには再試行できる理由を伝えるので、サーバーが戻れば再接続してくる
+async fn close_all_tunnels<T: Transport>(clients: &ClientMap<T>) {
+    let done_rxs: Vec<_> = {
+        let mut clients = clients.write().await;
+        clients
+            .values_mut()
+            .filter_map(|info| info.close(ErrorCode::ShuttingDown, "server shutting down"))
+            .collect()
+    };
+    if done_rxs.is_empty() {
+        return;
+    }
+
+    info!("Closing {} tunnels", done_rxs.len());
+    let all_closed = async {
+        for done_rx in done_rxs {
+            let _ = done_rx.await;
+        }
+    };
+    if timeout(SHUTDOWN_TIMEOUT, all_closed).await.is_err() {
+        warn!("Timeout waiting for tunnels to close");
+    }
+}
+
+/// 訪問者用ポートの設定をログに出す
+fn log_port_settings(config: &ServerConfig) {
+    let ranges: Vec<String> = config.port_ranges.iter().map(|r| r.to_string()).collect();
+    info!(
+        "Visitor ports: {} on {}",
+        ranges.join(", "),
+        config.visitor_bind_ip
+    );
+    if !config.excluded_ports.is_empty() {
+        let excluded: Vec<String> = config.excluded_ports.iter().map(|r| r.to_string()).collect();
+        info!("Excluded ports: {}", excluded.join(", "));
+    }
+}
+
+/// 再読み込みした設定を反映する
+/// 新しいポート設定は以降の割り当てから使い、失効したトークンのトンネルは閉じる
+async fn apply_config<T: Transport>(
+    old: &ServerConfig,
+    new: &ServerConfig,
+    allocator: &PortAllocator,
+    clients: &ClientMap<T>,
+) {
+    info!("Applying reloaded configuration");
+    if old.bind_addr != new.bind_addr || old.transport != new.transport || old.admin != new.admin {
+        warn!("Changes to bind_addr, transport and admin take effect after a restart");
+    }
+
+    if old.port_ranges != new.port_ranges
+        || old.excluded_ports != new.excluded_ports
+        || old.visitor_bind_ip != new.visitor_bind_ip
+    {
+        allocator
+            .update(new.visitor_ports(), new.visitor_bind_ip)
+            .await;
+        log_port_settings(new);
+    }
+
+    if old.tokens.is_empty() && !new.tokens.is_empty() {
+        info!("Authentication is now enabled");
+    } else if !old.tokens.is_empty() && new.tokens.is_empty() {
+        warn!("No tokens configured, authentication is disabled");
+    }
+
+    let mut clients = clients.write().await;
+    for info in clients.values_mut() {
+        if let Some((code, reason)) =
+            revoke_reason(new, info.token.as_deref(), info.name.as_deref())
+        {
+            info!(
+                "Closing tunnel on port {}: {}",
+                info.assigned_port, reason
+            );
+            info.close(code, reason);
         }
     }
 }
 
+/// 新しい設定でトンネルを続けられない理由
+fn revoke_reason(
+    config: &ServerConfig,
+    token: Option<&str>,
+    name: Option<&str>,
+) -> Option<(ErrorCode, &'static str)> {
+    if config.tokens.is_empty() {
+        return None;
+    }
+    match token {
+        Some(token) if config.token(token).is_some() => {}
+        Some(_) => return Some((ErrorCode::TokenRevoked, "token revoked")),
+        None => return Some((ErrorCode::AuthFailed, "authentication required")),
+    }
+    match name.and_then(|name| config.name_owner(name)) {
+        Some(owner) if Some(owner) != token => Some((ErrorCode::NameReserved, "name is reserved")),
+        _ => None,
+    }
+}
+
 /// 接続を処理
-async fn handle_connection(
-    mut stream: TcpStream,
+async fn handle_connection<T: Transport>(
+    mut stream: T::Stream,
     addr: SocketAddr,
+    config: Arc<ServerConfig>,
     allocator: Arc<PortAllocator>,
-    clients: Arc<RwLock<HashMap<SocketAddr, ClientInfo>>>,
+    clients: ClientMap<T>,
+    names: NameBindings,
+    events: broadcast::Sender<ServerEvent>,
 ) -> Result<()> {
     // 最初のメッセージを受信
-    let msg = timeout(Duration::from_secs(10), Message::read_from(&mut stream))
+    let mut msg = timeout(HANDSHAKE_TIMEOUT, Message::read_from(&mut stream))
         .await
         .context("Timeout waiting for initial message")??;
 
+    // 新しいクライアントはコントロールチャネルの最初に Hello でバージョンを伝えてくる
+    // Hello なしの TunnelRequest はバージョン 1 の古いクライアントとして扱う
+    let mut structured_errors = false;
+    if let Message::Hello {
+        version,
+        capabilities,
+    } = msg
+    {
+        structured_errors = capabilities.iter().any(|name| name == "error");
+        negotiate_version(&mut stream, addr, version, &capabilities, &events).await?;
+        msg = timeout(HANDSHAKE_TIMEOUT, Message::read_from(&mut stream))
+            .await
+            .context("Timeout waiting for TunnelRequest")??;
+        if !matches!(msg, Message::TunnelRequest { .. }) {
+            anyhow::bail!("Expected TunnelRequest after Hello, got {:?}", msg);
+        }
+    }
+
     match msg {
-        Message::TunnelRequest { local_port } => {
+        Message::TunnelRequest {
+            local_port,
+            remote_port,
+            name,
+            protocol,
+            multiplex,
+            pool_size,
+        } => {
             // 新しいコントロールチャネル
-            handle_control_channel(stream, addr, local_port, allocator, clients).await
+            let request = TunnelRequest {
+                local_port,
+                remote_port,
+                name,
+                protocol,
+                multiplex,
+                pool_size,
+                structured_errors,
+            };
+            handle_control_channel(stream, addr, request, config, allocator, clients, names, events)
+                .await
         }
-        _ => {
+        Message::DataChannelHello {
+            session,
+            nonce,
+            visitor_id,
+        } => {
             // データチャネルとして処理
-            handle_data_channel(stream, addr, clients).await
+            handle_data_channel(stream, addr, session, nonce, visitor_id, clients).await
+        }
+        Message::MuxChannelHello { session } => {
+            // 多重化用の接続として処理
+            handle_mux_channel(stream, addr, session, clients).await
         }
+        msg => Err(anyhow::anyhow!("Unexpected initial message: {:?}", msg)),
     }
 }
 
-/// コントロールチャネルを処理
-async fn handle_control_channel(
-    mut stream: TcpStream,
+/// クライアントのプロトコルバージョンを確認し、話せるなら自分の Hello を返す
+async fn negotiate_version<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
     addr: SocketAddr,
+    version: u32,
+    capabilities: &[String],
+    events: &broadcast::Sender<ServerEvent>,
+) -> Result<()> {
+    if version != PROTOCOL_VERSION {
+        let reason = format!(
+            "unsupported protocol version {} (server speaks {})",
+            version, PROTOCOL_VERSION
+        );
+        let structured_errors = capabilities.iter().any(|name| name == "error");
+        reject(
+            stream,
+            addr,
+            structured_errors,
+            ErrorCode::UnsupportedVersion,
+            &reason,
+            events,
+        )
+        .await;
+        anyhow::bail!("Client {} rejected: {}", addr, reason);
+    }
+
+    debug!(
+        "Client {} speaks protocol version {} (capabilities: {})",
+        addr,
+        version,
+        capabilities.join(", ")
+    );
+    Message::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: protocol::capabilities(),
+    }
+    .write_to(stream)
+    .await
+    .context("Failed to send Hello")
+}
+
+/// トンネルを作れない・続けられない理由をクライアントに伝えるメッセージ
+/// `Error` を理解するクライアントには種類と再試行の可否も伝え、古いクライアントには `TunnelRejected` を送る
+fn rejection(structured_errors: bool, code: ErrorCode, reason: &str) -> Message {
+    if structured_errors {
+        Message::Error {
+            code,
+            message: reason.to_string(),
+            retryable: code.is_retryable(),
+        }
+    } else {
+        Message::TunnelRejected {
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// トンネルを作れない理由をクライアントに伝え、`ServerEvent::TunnelRejected` を通知する
+async fn reject<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    addr: SocketAddr,
+    structured_errors: bool,
+    code: ErrorCode,
+    reason: &str,
+    events: &broadcast::Sender<ServerEvent>,
+) {
+    let _ = rejection(structured_errors, code, reason)
+        .write_to(stream)
+        .await;
+    let _ = events.send(ServerEvent::TunnelRejected {
+        client_addr: addr,
+        code,
+        reason: reason.to_string(),
+    });
+}
+
+/// クライアントからのトンネル作成リクエスト
+struct TunnelRequest {
     local_port: u16,
+    remote_port: Option<u16>,
+    name: Option<String>,
+    protocol: ServiceProtocol,
+    multiplex: bool,
+    pool_size: u16,
+    /// クライアントが `Error` メッセージを理解するか
+    structured_errors: bool,
+}
+
+/// 訪問者を受け付けるソケット
+enum VisitorListener {
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+}
+
+/// コントロールチャネルを処理
+#[allow(clippy::too_many_arguments)]
+async fn handle_control_channel<T: Transport>(
+    mut stream: T::Stream,
+    addr: SocketAddr,
+    request: TunnelRequest,
+    config: Arc<ServerConfig>,
     allocator: Arc<PortAllocator>,
-    clients: Arc<RwLock<HashMap<SocketAddr, ClientInfo>>>,
+    clients: ClientMap<T>,
+    names: NameBindings,
+    events: broadcast::Sender<ServerEvent>,
 ) -> Result<()> {
-    info!("Control channel from {} (local port: {})", addr, local_port);
+    let TunnelRequest {
+        local_port,
+        remote_port,
+        name,
+        protocol,
+        multiplex,
+        pool_size,
+        structured_errors,
+    } = request;
+    match name.as_deref() {
+        Some(name) => info!(
+            "Control channel from {} (local port: {}/{}, name: {})",
+            addr, local_port, protocol, name
+        ),
+        None => info!(
+            "Control channel from {} (local port: {}/{})",
+            addr, local_port, protocol
+        ),
+    }
 
-    // ポートを割り当て
-    let assigned_port = allocator
-        .allocate()
-        .await
-        .context("Failed to allocate port")?;
+    // ポートを割り当てる前に認証する
+    let token = authenticate(&mut stream, addr, &config, structured_errors, &events).await?;
+
+    // トークンごとの制限を確認
+    if let Err((code, reason)) =
+        check_limits(&config, token.as_deref(), name.as_deref(), &clients).await
+    {
+        reject(&mut stream, addr, structured_errors, code, &reason, &events).await;
+        anyhow::bail!("Tunnel from {} rejected: {}", addr, reason);
+    }
+
+    // 同じ名前のトンネルが残っていれば（再起動したクライアントなど）置き換えてポートを空ける
+    let mut preferred_port = remote_port;
+    if let Some(name) = name.as_deref() {
+        replace_named_tunnel(&clients, name).await;
+        if preferred_port.is_none() {
+            preferred_port = names.lock().await.get(name).map(|binding| binding.port);
+        }
+    }
+
+    // ポートを割り当て（希望のポートが使えなければ空いているポート）
+    let assigned_port = match preferred_port {
+        Some(port) => allocator.allocate_preferred(port).await,
+        None => allocator.allocate().await,
+    };
+    let assigned_port = match assigned_port {
+        Ok(port) => port,
+        Err(e) => {
+            reject(
+                &mut stream,
+                addr,
+                structured_errors,
+                ErrorCode::PortsExhausted,
+                "port range exhausted",
+                &events,
+            )
+            .await;
+            return Err(e).context("Failed to allocate port");
+        }
+    };
 
+    if let Some(port) = preferred_port.filter(|port| *port != assigned_port) {
+        warn!(
+            "Port {} requested by {} is not available, assigned {} instead",
+            port, addr, assigned_port
+        );
+    }
     info!("Assigned port {} to {}", assigned_port, addr);
 
     // ポートでリスナー起動
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", assigned_port))
-        .await
-        .with_context(|| format!("Failed to bind to port {}", assigned_port))?;
-
-    // レスポンス送信
-    Message::TunnelResponse { assigned_port }
-        .write_to(&mut stream)
-        .await
-        .context("Failed to send TunnelResponse")?;
+    let listener = match protocol {
+        ServiceProtocol::Tcp => allocator.bind(assigned_port).await.map(VisitorListener::Tcp),
+        ServiceProtocol::Udp => allocator.bind_udp(assigned_port).await.map(VisitorListener::Udp),
+    };
+    let listener = match listener {
+        Ok(listener) => listener,
+        Err(e) => {
+            allocator.release(assigned_port).await;
+            let reason = format!("failed to open port {}", assigned_port);
+            reject(
+                &mut stream,
+                addr,
+                structured_errors,
+                ErrorCode::BindFailed,
+                &reason,
+                &events,
+            )
+            .await;
+            return Err(e).with_context(|| format!("Failed to bind to port {}", assigned_port));
+        }
+    };
 
-    info!("Tunnel established for {} on port {}", addr, assigned_port);
+    // データチャネルの照合に使うセッショントークンを発行
+    let session = new_session_token();
 
-    // データチャネルキュー
-    let (data_tx, mut data_rx) = mpsc::channel::<TcpStream>(32);
+    // データチャネル待ちの訪問者テーブル
+    let pending_visitors: PendingVisitors<T> = Arc::new(Mutex::new(HashMap::new()));
 
     // コントロールメッセージチャネル
     let (control_tx, mut control_rx) = mpsc::channel::<Message>(32);
+    let (close_tx, mut close_rx) = oneshot::channel::<CloseRequest>();
+    let (mux_tx, mux_rx) = oneshot::channel::<T::Stream>();
 
-    // クライアント情報を保存
-    {
-        let mut clients = clients.write().await;
-        clients.insert(
-            addr,
-            ClientInfo {
-                assigned_port,
-                data_channel_tx: data_tx,
-                control_channel_tx: control_tx,
-            },
-        );
+    // 待機中のデータチャネルは多重化しない場合だけ受け入れる
+    let pool_size = if multiplex {
+        0
+    } else {
+        pool_size.min(MAX_POOL_SIZE)
+    };
+    let stats = Arc::new(TunnelStats::default());
+    let (data_channel_tx, idle_rx) = if pool_size > 0 {
+        let (tx, rx) = mpsc::channel::<T::Stream>(pool_size as usize);
+        (Some(tx), Some(rx))
+    } else {
+        (None, None)
+    };
+
+    // 名前とポートの対応を覚えておく
+    if let Some(name) = name.as_deref() {
+        bind_name(&names, &allocator, name, token.as_deref(), assigned_port, &session).await;
     }
 
-    // 訪問者接続を待機するタスク
-    let data_tx_clone = {
-        let clients = clients.read().await;
-        clients.get(&addr).map(|info| info.control_channel_tx.clone())
+    // クライアント情報を保存
+    // 多重化用の接続はレスポンスの直後に届くので、レスポンスより先に登録しておく
+    let client_info = ClientInfo {
+        addr,
+        assigned_port,
+        local_port,
+        protocol,
+        name,
+        token,
+        pending_visitors: pending_visitors.clone(),
+        nonces: auth::NonceHistory::new(NONCE_HISTORY),
+        control_channel_tx: control_tx.clone(),
+        close_tx: Some(close_tx),
+        mux_tx: multiplex.then_some(mux_tx),
+        data_channel_tx,
+        connected_since: SystemTime::now(),
+        stats: stats.clone(),
     };
+    let summary = client_info.summary();
+    clients.write().await.insert(session.clone(), client_info);
 
-    if let Some(control_tx) = data_tx_clone {
-        tokio::spawn(async move {
-            loop {
-                match listener.accept().await {
-                    Ok((visitor_stream, visitor_addr)) => {
-                        info!("Visitor connected to port {} from {}", assigned_port, visitor_addr);
-
-                        // クライアントにデータチャネル作成を要求
-                        if let Err(e) = control_tx.send(Message::CreateDataChannel).await {
-                            error!("Failed to request data channel: {}", e);
-                            break;
-                        }
-
-                        // データチャネルが来るまで待機
-                        match tokio::time::timeout(Duration::from_secs(10), data_rx.recv()).await {
-                            Ok(Some(data_stream)) => {
-                                // 訪問者とデータチャネルを接続
-                                tokio::spawn(async move {
-                                    if let Err(e) = forward_traffic(visitor_stream, data_stream).await {
-                                        debug!("Traffic forwarding error: {}", e);
-                                    }
-                                });
-                            }
-                            Ok(None) => {
-                                error!("Data channel closed");
-                                break;
-                            }
-                            Err(_) => {
-                                warn!("Timeout waiting for data channel");
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        error!("Failed to accept visitor: {}", e);
-                        break;
-                    }
-                }
+    // レスポンス送信
+    let response = Message::TunnelResponse {
+        assigned_port,
+        session: session.clone(),
+        multiplex,
+        pool_size,
+    }
+    .write_to(&mut stream)
+    .await;
+    if let Err(e) = response {
+        if let Some(client_info) = clients.write().await.remove(&session) {
+            if let Some(name) = client_info.name.as_deref() {
+                release_name(&names, name, &session).await;
             }
-            info!("Listener for port {} stopped", assigned_port);
-        });
+        }
+        allocator.release(assigned_port).await;
+        return Err(e).context("Failed to send TunnelResponse");
     }
 
+    info!(
+        "Tunnel established for {} on port {}/{}",
+        addr, assigned_port, protocol
+    );
+    let _ = events.send(ServerEvent::TunnelOpened(summary));
+
+    // 多重化用の接続を待つ。届かなければ訪問者ごとにデータチャネルを張ってもらう
+    let mux = if multiplex {
+        match timeout(DATA_CHANNEL_TIMEOUT, mux_rx).await {
+            Ok(Ok(conn)) => {
+                debug!("Multiplexed connection established for {}", addr);
+                // ストリームを開くのはサーバーだけ。クライアントが開いたストリームは閉じる
+                let mut session = mux::Session::new(conn, mux::Side::Server);
+                session.reject_incoming();
+                Some(session)
+            }
+            _ => {
+                warn!(
+                    "No multiplexed connection from {}, falling back to a connection per visitor",
+                    addr
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let channels = match &mux {
+        Some(mux) => DataChannels::Mux(mux.control()),
+        None => DataChannels::Dial {
+            pending_visitors,
+            control_tx,
+            idle_rx,
+        },
+    };
+    let mux_control = mux.as_ref().map(|mux| mux.control());
+
+    // 訪問者接続を待機するタスク
+    let listener_handle = tokio::spawn(async move {
+        match listener {
+            VisitorListener::Tcp(listener) => {
+                serve_tcp_visitors(listener, assigned_port, channels, stats).await
+            }
+            VisitorListener::Udp(socket) => {
+                serve_udp_visitors(socket, assigned_port, channels, stats, udp::MAX_UDP_FLOWS)
+                    .await
+            }
+        }
+        info!("Listener for port {} stopped", assigned_port);
+    });
+
     // ハートビートループ
+    let (read_half, mut stream) = tokio::io::split(stream);
+    let mut reader = MessageReader::spawn(read_half);
     let mut heartbeat_interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let heartbeat_deadline = tokio::time::sleep(HEARTBEAT_TIMEOUT);
+    tokio::pin!(heartbeat_deadline);
+    let mut closed: Option<(ErrorCode, oneshot::Sender<()>)> = None;
 
     loop {
         tokio::select! {
             // クライアントからのメッセージを受信
-            msg_result = Message::read_from(&mut stream) => {
+            msg_result = reader.recv() => {
+                if msg_result.is_ok() {
+                    heartbeat_deadline
+                        .as_mut()
+                        .reset(tokio::time::Instant::now() + HEARTBEAT_TIMEOUT);
+                }
                 match msg_result {
                     Ok(Message::Heartbeat) => {
+                        // 双方が定期的に送信するので返信はしない
                         debug!("Received heartbeat from {}", addr);
-                        Message::Heartbeat.write_to(&mut stream).await?;
+                    }
+                    Ok(Message::Unknown) => {
+                        // 新しいクライアントの知らないメッセージは無視する
+                        debug!("Ignoring unknown message from {}", addr);
                     }
                     Ok(msg) => {
                         warn!("Unexpected message from {}: {:?}", addr, msg);
@@ -205,6 +904,32 @@ async fn handle_control_channel(
                 }
             }
 
+            // 一定時間何も受信しなければ切断とみなす
+            _ = &mut heartbeat_deadline => {
+                info!("Heartbeat timeout for {}", addr);
+                break;
+            }
+
+            // 多重化用の接続が切れたらトンネルを作り直してもらう
+            _ = mux_closed(mux_control.as_ref()) => {
+                info!("Multiplexed connection closed for {}", addr);
+                break;
+            }
+
+            // サーバー側から閉じる（同じ名前のトンネルによる置き換え・トークンの失効）
+            Ok(request) = &mut close_rx => {
+                info!("Closing tunnel for {}: {}", addr, request.reason);
+                // 理由を伝えてから切断する。古いクライアントは `TunnelRejected` を受け取ると
+                // 再接続しないので、再試行できる理由（シャットダウン）なら何も送らない
+                if structured_errors || !request.code.is_retryable() {
+                    let _ = rejection(structured_errors, request.code, &request.reason)
+                        .write_to(&mut stream)
+                        .await;
+                }
+                closed = Some((request.code, request.done));
+                break;
+            }
+
             // 内部からのコントロールメッセージを送信
             Some(msg) = control_rx.recv() => {
                 if let Err(e) = msg.write_to(&mut stream).await {
@@ -225,50 +950,687 @@ async fn handle_control_channel(
     }
 
     // クリーンアップ
+    // リスナーを止めてポートを即座に再利用可能にする
     info!("Cleaning up client {}", addr);
+    listener_handle.abort();
+    let _ = listener_handle.await;
+    drop(mux);
     {
         let mut clients = clients.write().await;
-        if let Some(client_info) = clients.remove(&addr) {
+        if let Some(client_info) = clients.remove(&session) {
             allocator.release(client_info.assigned_port).await;
             info!("Released port {}", client_info.assigned_port);
+            if let Some(name) = client_info.name.as_deref() {
+                release_name(&names, name, &session).await;
+            }
+            let _ = events.send(ServerEvent::TunnelClosed {
+                tunnel: client_info.summary(),
+                code: closed.as_ref().map(|(code, _)| *code),
+            });
+        }
+    }
+    if let Some((_, done)) = closed {
+        let _ = done.send(());
+    }
+
+    Ok(())
+}
+
+/// TCP の訪問者を受け付け、訪問者ごとにデータチャネルを要求して接続する
+async fn serve_tcp_visitors<T: Transport>(
+    listener: TcpListener,
+    assigned_port: u16,
+    mut channels: DataChannels<T>,
+    stats: Arc<TunnelStats>,
+) {
+    let mut next_visitor_id: u64 = 0;
+    loop {
+        match listener.accept().await {
+            Ok((visitor_stream, visitor_addr)) => {
+                next_visitor_id += 1;
+                let visitor_id = next_visitor_id;
+                info!(
+                    "Visitor {} connected to port {} from {}",
+                    visitor_id, assigned_port, visitor_addr
+                );
+
+                let pending = match channels.request(visitor_id).await {
+                    Ok(pending) => pending,
+                    Err(e) => {
+                        error!("Failed to request data channel: {}", e);
+                        break;
+                    }
+                };
+
+                // データチャネルの到着は訪問者ごとに並行して待つ
+                let stats = stats.clone();
+                tokio::spawn(async move {
+                    if let Some(data_stream) = pending.wait().await {
+                        // 訪問者とデータチャネルを接続
+                        let _visitor = stats.visitor();
+                        let data_stream = CountedStream::new(data_stream, stats);
+                        if let Err(e) = forward_traffic(visitor_stream, data_stream).await {
+                            debug!("Traffic forwarding error: {}", e);
+                        }
+                    }
+                });
+            }
+            Err(e) => {
+                error!("Failed to accept visitor: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// UDP の訪問者を受け付ける
+/// 送信元アドレスごとにフローを作り、フローごとのデータチャネルでデータグラムを運ぶ
+/// フローは `UDP_IDLE_TIMEOUT` の間通信がなければ閉じる
+/// フローが `max_flows` 個あるうちは、新しい送信元からのデータグラムを捨てる
+async fn serve_udp_visitors<T: Transport>(
+    socket: UdpSocket,
+    assigned_port: u16,
+    mut channels: DataChannels<T>,
+    stats: Arc<TunnelStats>,
+    max_flows: usize,
+) {
+    let socket = Arc::new(socket);
+    let flows: UdpFlows = Arc::new(Mutex::new(HashMap::new()));
+    let mut buf = vec![0u8; udp::MAX_DATAGRAM_SIZE];
+    let mut next_visitor_id: u64 = 0;
+
+    loop {
+        let (len, visitor_addr) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                // ICMP port unreachable などで一時的に失敗することがある
+                debug!("Failed to receive datagram on port {}: {}", assigned_port, e);
+                continue;
+            }
+        };
+        let datagram = buf[..len].to_vec();
+
+        // 既存のフローがあればそちらに渡す
+        let mut flows_guard = flows.lock().await;
+        let datagram = match flows_guard.get(&visitor_addr) {
+            Some(flow_tx) => match flow_tx.try_send(datagram) {
+                Ok(()) => continue,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    // UDP なので詰まっているフローのデータグラムは捨てる
+                    debug!("UDP flow from {} is congested, dropping datagram", visitor_addr);
+                    continue;
+                }
+                Err(mpsc::error::TrySendError::Closed(datagram)) => datagram,
+            },
+            None if flows_guard.len() >= max_flows => {
+                debug!(
+                    "Too many UDP flows on port {}, dropping datagram from {}",
+                    assigned_port, visitor_addr
+                );
+                continue;
+            }
+            None => datagram,
+        };
+
+        next_visitor_id += 1;
+        let visitor_id = next_visitor_id;
+        info!(
+            "UDP visitor {} on port {} from {}",
+            visitor_id, assigned_port, visitor_addr
+        );
+
+        let (flow_tx, flow_rx) = mpsc::channel(udp::UDP_FLOW_QUEUE);
+        let _ = flow_tx.try_send(datagram);
+        flows_guard.insert(visitor_addr, flow_tx.clone());
+        drop(flows_guard);
+
+        let pending = match channels.request(visitor_id).await {
+            Ok(pending) => pending,
+            Err(e) => {
+                error!("Failed to request data channel: {}", e);
+                break;
+            }
+        };
+
+        let socket = socket.clone();
+        let flows = flows.clone();
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            if let Some(data_stream) = pending.wait().await {
+                // UDP はデータチャネル上のフレームの長さも含めて数える
+                let _visitor = stats.visitor();
+                let data_stream = CountedStream::new(data_stream, stats);
+                if let Err(e) = udp::relay(data_stream, socket, Some(visitor_addr), flow_rx).await
+                {
+                    debug!("UDP relay error for visitor {}: {}", visitor_id, e);
+                }
+            }
+            // 同じアドレスの新しいフローに置き換わっていなければ取り除く
+            let mut flows = flows.lock().await;
+            if flows
+                .get(&visitor_addr)
+                .map_or(false, |tx| tx.same_channel(&flow_tx))
+            {
+                flows.remove(&visitor_addr);
+            }
+            debug!("UDP flow for visitor {} closed", visitor_id);
+        });
+    }
+}
+
+/// 訪問者用のデータチャネルの用意の仕方
+enum DataChannels<T: Transport> {
+    /// 訪問者ごとにクライアントから新しい接続を張ってもらう
+    /// 待機中のデータチャネルがあればそれを使い、使った分の補充を頼む
+    Dial {
+        pending_visitors: PendingVisitors<T>,
+        control_tx: mpsc::Sender<Message>,
+        idle_rx: Option<mpsc::Receiver<T::Stream>>,
+    },
+    /// 多重化した接続の上にストリームを開く
+    Mux(mux::Control),
+}
+
+/// 用意を始めたデータチャネル
+enum PendingChannel<T: Transport> {
+    /// クライアントからの接続待ち
+    Dial {
+        pending_visitors: PendingVisitors<T>,
+        visitor_id: u64,
+        data_rx: oneshot::Receiver<T::Stream>,
+    },
+    /// すぐに使えるデータチャネル（待機中だったデータチャネル・多重化したストリーム）
+    Ready(Box<dyn DataStream>),
+}
+
+/// 訪問者とつなぐデータチャネル
+trait DataStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> DataStream for S {}
+
+impl<T: Transport> DataChannels<T> {
+    /// 訪問者用のデータチャネルの用意を始める
+    /// 訪問者ごとにつなぐ場合は、受け取り口を登録してからクライアントに作成を要求する
+    async fn request(&mut self, visitor_id: u64) -> Result<PendingChannel<T>> {
+        match self {
+            DataChannels::Dial {
+                pending_visitors,
+                control_tx,
+                idle_rx,
+            } => {
+                if let Some(idle_rx) = idle_rx.as_mut() {
+                    while let Ok(mut stream) = idle_rx.try_recv() {
+                        // 使った分（切れていた分も）を補充してもらう
+                        control_tx
+                            .send(Message::CreateDataChannel {
+                                visitor_id: POOLED_VISITOR_ID,
+                            })
+                            .await
+                            .context("Control channel closed")?;
+                        // どの訪問者に割り当てたかを待機中のデータチャネル上で伝える
+                        let activated = Message::CreateDataChannel { visitor_id }
+                            .write_to(&mut stream)
+                            .await;
+                        match activated {
+                            Ok(()) => {
+                                debug!("Using idle data channel for visitor {}", visitor_id);
+                                return Ok(PendingChannel::Ready(Box::new(stream)));
+                            }
+                            Err(e) => debug!("Dropping broken idle data channel: {}", e),
+                        }
+                    }
+                    debug!("No idle data channel for visitor {}, requesting one", visitor_id);
+                }
+
+                let (data_tx, data_rx) = oneshot::channel();
+                pending_visitors.lock().await.insert(visitor_id, data_tx);
+                control_tx
+                    .send(Message::CreateDataChannel { visitor_id })
+                    .await
+                    .context("Control channel closed")?;
+                Ok(PendingChannel::Dial {
+                    pending_visitors: pending_visitors.clone(),
+                    visitor_id,
+                    data_rx,
+                })
+            }
+            DataChannels::Mux(control) => {
+                Ok(PendingChannel::Ready(Box::new(control.open().await?)))
+            }
+        }
+    }
+}
+
+impl<T: Transport> PendingChannel<T> {
+    /// データチャネルが届くのを待つ
+    async fn wait(self) -> Option<Box<dyn DataStream>> {
+        let (pending_visitors, visitor_id, data_rx) = match self {
+            PendingChannel::Ready(stream) => return Some(stream),
+            PendingChannel::Dial {
+                pending_visitors,
+                visitor_id,
+                data_rx,
+            } => (pending_visitors, visitor_id, data_rx),
+        };
+        match timeout(DATA_CHANNEL_TIMEOUT, data_rx).await {
+            Ok(Ok(data_stream)) => Some(Box::new(data_stream)),
+            Ok(Err(_)) => {
+                debug!("Control channel closed before data channel for visitor {}", visitor_id);
+                None
+            }
+            Err(_) => {
+                // 遅れて届いたデータチャネルは孤児として破棄される
+                pending_visitors.lock().await.remove(&visitor_id);
+                warn!("Timeout waiting for data channel for visitor {}", visitor_id);
+                None
+            }
+        }
+    }
+}
+
+/// 多重化用の接続が切れるまで待つ（多重化していなければ終わらない）
+async fn mux_closed(control: Option<&mux::Control>) {
+    match control {
+        Some(control) => control.closed().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// 管理 API の操作を実行
+async fn handle_admin_command<T: Transport>(
+    command: AdminCommand,
+    clients: &ClientMap<T>,
+    allocator: &PortAllocator,
+    names: &NameBindings,
+) -> AdminReply {
+    match command {
+        AdminCommand::ListTunnels => {
+            let clients = clients.read().await;
+            let mut tunnels: Vec<TunnelSummary> = clients.values().map(ClientInfo::summary).collect();
+            tunnels.sort_by_key(|tunnel| tunnel.remote_port);
+            AdminReply::Tunnels(tunnels)
+        }
+        AdminCommand::Disconnect(port) => {
+            let done_rx = {
+                let mut clients = clients.write().await;
+                match clients.values_mut().find(|info| info.assigned_port == port) {
+                    Some(info) => {
+                        info.close(ErrorCode::Disconnected, "disconnected by the administrator")
+                    }
+                    None => return AdminReply::NotFound(format!("no tunnel on port {}", port)),
+                }
+            };
+            info!("Disconnecting tunnel on port {} (admin API)", port);
+            // 閉じ終わってから応答し、すぐ後の一覧に残らないようにする
+            if let Some(done_rx) = done_rx {
+                if timeout(Duration::from_secs(5), done_rx).await.is_err() {
+                    warn!("Timeout waiting for tunnel on port {} to close", port);
+                }
+            }
+            AdminReply::Done
+        }
+        AdminCommand::ListPorts => AdminReply::Ports(allocator.usage().await),
+        AdminCommand::ReservePort(port) => match allocator.hold(port).await {
+            Ok(()) => {
+                info!("Port {} is held back from allocation (admin API)", port);
+                AdminReply::Done
+            }
+            Err(e) => AdminReply::Conflict(e.to_string()),
+        },
+        AdminCommand::ReleasePort(port) => {
+            if allocator.unhold(port).await {
+                info!("Port {} can be allocated again (admin API)", port);
+                return AdminReply::Done;
+            }
+            // 名前付きトンネル用の予約は、そのポートを使っているトンネルがなければ解除する
+            if clients
+                .read()
+                .await
+                .values()
+                .any(|info| info.assigned_port == port)
+            {
+                return AdminReply::Conflict(format!(
+                    "port {} is in use by a tunnel, disconnect it first",
+                    port
+                ));
+            }
+            let released = {
+                let mut names = names.lock().await;
+                let before = names.len();
+                names.retain(|_, binding| binding.port != port);
+                names.len() != before
+            };
+            if !released {
+                return AdminReply::NotFound(format!("port {} is not reserved", port));
+            }
+            allocator.unreserve(port).await;
+            info!("Released the reservation of port {} (admin API)", port);
+            AdminReply::Done
+        }
+    }
+}
+
+/// 同じ名前のトンネルがあれば閉じて、ポートが解放されるまで待つ
+async fn replace_named_tunnel<T: Transport>(clients: &ClientMap<T>, name: &str) {
+    let done_rx = {
+        let mut clients = clients.write().await;
+        clients
+            .values_mut()
+            .find(|info| info.name.as_deref() == Some(name))
+            .and_then(|info| {
+                info.close(
+                    ErrorCode::Replaced,
+                    "replaced by a newer tunnel with the same name",
+                )
+            })
+    };
+
+    if let Some(done_rx) = done_rx {
+        info!("Replacing existing tunnel named {}", name);
+        if timeout(Duration::from_secs(5), done_rx).await.is_err() {
+            warn!("Timeout waiting for tunnel named {} to close", name);
+        }
+    }
+}
+
+/// 名前と割り当てたポートの対応を記録して、ポートを予約する
+/// トークンごとに覚える名前の数には上限があり、超えたら使われていない一番古い名前を忘れる
+async fn bind_name(
+    names: &NameBindings,
+    allocator: &PortAllocator,
+    name: &str,
+    token: Option<&str>,
+    port: u16,
+    session: &str,
+) {
+    let mut names = names.lock().await;
+    if !names.contains_key(name) {
+        let owned = names
+            .values()
+            .filter(|binding| binding.token.as_deref() == token)
+            .count();
+        if owned >= MAX_NAMES_PER_TOKEN {
+            let oldest = names
+                .iter()
+                .filter(|(_, binding)| {
+                    binding.token.as_deref() == token && binding.session.is_none()
+                })
+                .min_by_key(|(_, binding)| binding.last_used)
+                .map(|(name, _)| name.clone());
+            let Some(oldest) = oldest else {
+                warn!(
+                    "Too many named tunnels for one token, not remembering port {} for {}",
+                    port, name
+                );
+                return;
+            };
+            if let Some(binding) = names.remove(&oldest) {
+                unreserve_unbound(&names, allocator, binding.port).await;
+                debug!("Forgot tunnel name {} to make room for {}", oldest, name);
+            }
+        }
+    }
+
+    let previous = names.insert(
+        name.to_string(),
+        NameBinding {
+            port,
+            token: token.map(str::to_string),
+            session: Some(session.to_string()),
+            last_used: Instant::now(),
+        },
+    );
+    if let Some(previous) = previous.filter(|binding| binding.port != port) {
+        unreserve_unbound(&names, allocator, previous.port).await;
+    }
+    allocator.reserve(port).await;
+}
+
+/// トンネルが閉じたら名前を使われていない状態にする。ポートは `NAME_BINDING_TTL` の間予約しておく
+/// 同じ名前の新しいトンネルに置き換わっていれば何もしない
+async fn release_name(names: &NameBindings, name: &str, session: &str) {
+    if let Some(binding) = names.lock().await.get_mut(name) {
+        if binding.session.as_deref() == Some(session) {
+            binding.session = None;
+            binding.last_used = Instant::now();
+        }
+    }
+}
+
+/// 使われないまま `NAME_BINDING_TTL` が過ぎた名前を忘れて、ポートの予約を解除する
+async fn expire_names(names: &NameBindings, allocator: &PortAllocator) {
+    let mut names = names.lock().await;
+    let expired: Vec<String> = names
+        .iter()
+        .filter(|(_, binding)| {
+            binding.session.is_none() && binding.last_used.elapsed() >= NAME_BINDING_TTL
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
+    for name in expired {
+        if let Some(binding) = names.remove(&name) {
+            unreserve_unbound(&names, allocator, binding.port).await;
+            info!(
+                "Tunnel name {} has not been used for {:?}, released port {}",
+                name, NAME_BINDING_TTL, binding.port
+            );
         }
     }
+}
+
+/// どの名前にも使われていないポートの予約を解除する
+async fn unreserve_unbound(
+    names: &HashMap<String, NameBinding>,
+    allocator: &PortAllocator,
+    port: u16,
+) {
+    if !names.values().any(|binding| binding.port == port) {
+        allocator.unreserve(port).await;
+    }
+}
 
+/// トークンごとの制限（予約された名前・同時トンネル数）を確認
+async fn check_limits<T: Transport>(
+    config: &ServerConfig,
+    token: Option<&str>,
+    name: Option<&str>,
+    clients: &ClientMap<T>,
+) -> Result<(), (ErrorCode, String)> {
+    if let Some(name) = name {
+        if let Some(owner) = config.name_owner(name) {
+            if Some(owner) != token {
+                return Err((ErrorCode::NameReserved, format!("name {} is reserved", name)));
+            }
+        }
+    }
+
+    let max_tunnels = token
+        .and_then(|token| config.token(token))
+        .and_then(|t| t.max_tunnels);
+    if let Some(max_tunnels) = max_tunnels {
+        // 同じ名前のトンネルは置き換えられるので数えない
+        let clients = clients.read().await;
+        let open = clients
+            .values()
+            .filter(|info| info.token.as_deref() == token)
+            .filter(|info| name.is_none() || info.name.as_deref() != name)
+            .count();
+        if open >= max_tunnels {
+            return Err((
+                ErrorCode::TunnelLimit,
+                format!("tunnel limit reached ({})", max_tunnels),
+            ));
+        }
+    }
     Ok(())
 }
 
+/// チャレンジ/レスポンス認証。認証に使われたトークンを返す
+/// トークンが設定されていなければ何もしない
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    addr: SocketAddr,
+    config: &ServerConfig,
+    structured_errors: bool,
+    events: &broadcast::Sender<ServerEvent>,
+) -> Result<Option<String>> {
+    if config.tokens.is_empty() {
+        return Ok(None);
+    }
+
+    let nonce = auth::new_nonce();
+    Message::AuthChallenge {
+        nonce: nonce.clone(),
+    }
+    .write_to(stream)
+    .await
+    .context("Failed to send AuthChallenge")?;
+
+    let msg = timeout(HANDSHAKE_TIMEOUT, Message::read_from(stream))
+        .await
+        .context("Timeout waiting for AuthResponse")??;
+
+    let token = match msg {
+        Message::AuthResponse { digest } => {
+            let tokens = config.tokens.iter().map(|t| t.token.as_str());
+            auth::verify_digest(tokens, &nonce, &digest)
+        }
+        _ => None,
+    };
+
+    let Some(token) = token else {
+        // 拒否理由を伝えてから切断する
+        reject(
+            stream,
+            addr,
+            structured_errors,
+            ErrorCode::AuthFailed,
+            "authentication failed",
+            events,
+        )
+        .await;
+        anyhow::bail!("Authentication failed for {}", addr);
+    };
+
+    debug!("Client {} authenticated", addr);
+    Ok(Some(token.to_string()))
+}
+
 /// データチャネルを処理
-async fn handle_data_channel(
-    stream: TcpStream,
+/// 送信元アドレスではなくセッショントークンでコントロールチャネルを特定し、
+/// 訪問者IDでデータチャネルを待っている訪問者と対応付ける
+/// 同じセッションで使われたnonceを持つデータチャネルは拒否する
+/// nonce はクライアントが選ぶ認証されない値なので、セッショントークンを知る相手の再送は防げない
+async fn handle_data_channel<T: Transport>(
+    stream: T::Stream,
     addr: SocketAddr,
-    clients: Arc<RwLock<HashMap<SocketAddr, ClientInfo>>>,
+    session: String,
+    nonce: String,
+    visitor_id: u64,
+    clients: ClientMap<T>,
 ) -> Result<()> {
-    debug!("Data channel from {}", addr);
+    debug!(
+        "Data channel from {} for visitor {} (nonce: {})",
+        addr, visitor_id, nonce
+    );
 
-    // クライアント情報を取得
-    let data_tx = {
-        let clients = clients.read().await;
-        clients
-            .get(&addr)
-            .map(|info| info.data_channel_tx.clone())
+    // クライアント情報を取得し、nonce を記録する
+    let channels = {
+        let mut clients = clients.write().await;
+        clients.get_mut(&session).map(|info| {
+            let fresh = !nonce.is_empty() && info.nonces.insert(&nonce);
+            (
+                fresh,
+                info.pending_visitors.clone(),
+                info.data_channel_tx.clone(),
+            )
+        })
     };
 
-    if let Some(data_tx) = data_tx {
-        // データチャネルをキューに追加
-        data_tx
-            .send(stream)
-            .await
-            .context("Failed to send data channel")?;
-        debug!("Data channel queued for {}", addr);
-    } else {
-        warn!("No control channel found for data channel from {}", addr);
+    let (pending_visitors, data_channel_tx) = match channels {
+        Some((true, pending_visitors, data_channel_tx)) => (pending_visitors, data_channel_tx),
+        Some((false, ..)) => {
+            warn!(
+                "Data channel from {} has an empty or reused nonce, dropping it",
+                addr
+            );
+            return Ok(());
+        }
+        None => {
+            warn!("No control channel found for data channel from {} (unknown session)", addr);
+            return Ok(());
+        }
+    };
+
+    // 待機中のデータチャネルは訪問者が来るまで置いておく
+    if visitor_id == POOLED_VISITOR_ID {
+        match data_channel_tx.map(|tx| tx.try_send(stream)) {
+            Some(Ok(())) => debug!("Idle data channel from {} added to the pool", addr),
+            Some(Err(_)) => warn!(
+                "Data channel pool is full, dropping idle data channel from {}",
+                addr
+            ),
+            None => warn!(
+                "Unexpected idle data channel from {}, pool is not enabled",
+                addr
+            ),
+        }
+        return Ok(());
+    }
+
+    // 対応する訪問者がいなければ（タイムアウト済み・不明なID）破棄する
+    let data_tx = pending_visitors.lock().await.remove(&visitor_id);
+    match data_tx {
+        Some(data_tx) => {
+            if data_tx.send(stream).is_err() {
+                warn!("Visitor {} is gone, dropping data channel from {}", visitor_id, addr);
+            } else {
+                debug!("Data channel paired with visitor {}", visitor_id);
+            }
+        }
+        None => {
+            warn!("No pending visitor {}, dropping orphan data channel from {}", visitor_id, addr);
+        }
+    }
+
+    Ok(())
+}
+
+/// 多重化用の接続を処理
+/// セッショントークンでコントロールチャネルを特定して受け渡す
+async fn handle_mux_channel<T: Transport>(
+    stream: T::Stream,
+    addr: SocketAddr,
+    session: String,
+    clients: ClientMap<T>,
+) -> Result<()> {
+    debug!("Multiplexed connection from {}", addr);
+
+    let mux_tx = {
+        let mut clients = clients.write().await;
+        clients.get_mut(&session).and_then(|info| info.mux_tx.take())
+    };
+    match mux_tx {
+        Some(mux_tx) => {
+            if mux_tx.send(stream).is_err() {
+                warn!("Control channel gave up waiting, dropping multiplexed connection from {}", addr);
+            }
+        }
+        None => {
+            warn!("Unexpected multiplexed connection from {} (unknown session)", addr);
+        }
     }
 
     Ok(())
 }
 
+/// 推測困難なセッショントークンを生成
+fn new_session_token() -> String {
+    hex::encode(rand::random::<[u8; 16]>())
+}
+
 /// トラフィックを転送
-async fn forward_traffic(visitor: TcpStream, data: TcpStream) -> Result<()> {
+async fn forward_traffic<D: AsyncRead + AsyncWrite>(visitor: TcpStream, data: D) -> Result<()> {
     let (mut visitor_read, mut visitor_write) = tokio::io::split(visitor);
     let (mut data_read, mut data_write) = tokio::io::split(data);
 
@@ -292,3 +1654,48 @@ async fn forward_traffic(visitor: TcpStream, data: TcpStream) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_udp_flow_limit() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (control_tx, mut control_rx) = mpsc::channel(32);
+        let channels = DataChannels::<TcpTransport>::Dial {
+            pending_visitors: Arc::new(Mutex::new(HashMap::new())),
+            control_tx,
+            idle_rx: None,
+        };
+        let handle = tokio::spawn(serve_udp_visitors(
+            socket,
+            addr.port(),
+            channels,
+            Arc::new(TunnelStats::default()),
+            2,
+        ));
+
+        // 送信元ごとにデータチャネルを要求する
+        let mut visitors = Vec::new();
+        for _ in 0..3 {
+            visitors.push(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        }
+        for visitor in &visitors[..2] {
+            visitor.send_to(b"hello", addr).await.unwrap();
+            match control_rx.recv().await.unwrap() {
+                Message::CreateDataChannel { visitor_id } => assert_ne!(visitor_id, POOLED_VISITOR_ID),
+                msg => panic!("Unexpected message: {:?}", msg),
+            }
+        }
+
+        // 上限に達したら新しい送信元は捨て、既存のフローはそのまま使う
+        visitors[2].send_to(b"hello", addr).await.unwrap();
+        visitors[0].send_to(b"again", addr).await.unwrap();
+        let more = timeout(Duration::from_millis(200), control_rx.recv()).await;
+        assert!(more.is_err());
+
+        handle.abort();
+    }
+}
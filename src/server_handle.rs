@@ -0,0 +1,261 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+
+use crate::admin::{AdminCommand, AdminReply, AdminRequest, TunnelSummary};
+use crate::config::{AdminConfig, PortRange, ServerConfig, TokenConfig, TransportConfig};
+use crate::error::Error;
+use crate::protocol::ErrorCode;
+use crate::server::{self, ServerHooks};
+
+/// サーバーで起きたこと（`ServerHandle::subscribe` で受け取る）
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ServerEvent {
+    /// トンネルが開いた
+    TunnelOpened(TunnelSummary),
+    /// トンネルが閉じた。`tunnel` の転送量は閉じた時点の値
+    TunnelClosed {
+        tunnel: TunnelSummary,
+        /// サーバー側から閉じた理由（クライアントの切断・ハートビートのタイムアウトなら `None`）
+        code: Option<ErrorCode>,
+    },
+    /// トンネルの作成を拒否した
+    TunnelRejected {
+        client_addr: SocketAddr,
+        code: ErrorCode,
+        reason: String,
+    },
+}
+
+/// サーバーをプロセスに組み込んで起動する
+///
+/// # 例
+/// ```no_run
+/// use rathole::ServerBuilder;
+///
+/// #[tokio::main]
+/// async fn main() -> anyhow::Result<()> {
+///     // ポート 0 なら空いているポートで待ち受ける
+///     let server = ServerBuilder::new("127.0.0.1:0")
+///         .token("secret")
+///         .start()
+///         .await?;
+///     println!("Listening on {}", server.local_addr());
+///
+///     let mut events = server.subscribe();
+///     tokio::spawn(async move {
+///         while let Ok(event) = events.recv().await {
+///             println!("{:?}", event);
+///         }
+///     });
+///
+///     tokio::signal::ctrl_c().await?;
+///     server.shutdown().await?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ServerBuilder {
+    config: ServerConfig,
+}
+
+impl ServerBuilder {
+    /// 認証なし・デフォルトのポート範囲で待ち受ける
+    pub fn new(bind_addr: impl Into<String>) -> Self {
+        Self::from_config(ServerConfig::new(bind_addr))
+    }
+
+    /// 設定から作成（設定ファイルは `ServerConfig::from_file` で読み込む）
+    pub fn from_config(config: ServerConfig) -> Self {
+        Self { config }
+    }
+
+    /// 受け付ける認証トークンを追加する
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.config.tokens.push(TokenConfig::new(token));
+        self
+    }
+
+    /// 訪問者用に割り当てるポート範囲（デフォルトの範囲を置き換える）
+    pub fn port_ranges(mut self, ranges: impl IntoIterator<Item = PortRange>) -> Self {
+        self.config.port_ranges = ranges.into_iter().collect();
+        self
+    }
+
+    /// 割り当てないポートを追加する
+    pub fn exclude_ports(mut self, range: PortRange) -> Self {
+        self.config.excluded_ports.push(range);
+        self
+    }
+
+    /// 訪問者用ポートを開くIPアドレス
+    pub fn visitor_bind_ip(mut self, ip: IpAddr) -> Self {
+        self.config.visitor_bind_ip = ip;
+        self
+    }
+
+    /// コントロールチャネル・データチャネルのトランスポート
+    pub fn transport(mut self, transport: TransportConfig) -> Self {
+        self.config.transport = transport;
+        self
+    }
+
+    /// 管理 API も待ち受ける
+    pub fn admin(mut self, admin: AdminConfig) -> Self {
+        self.config.admin = Some(admin);
+        self
+    }
+
+    /// サーバーを起動し、待ち受けを始めたら返る
+    pub async fn start(self) -> Result<ServerHandle, Error> {
+        self.config.validate().map_err(Error::config)?;
+        let (_config_tx, config_rx) = watch::channel(Arc::new(self.config));
+        let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let mut hooks = ServerHooks::new();
+        hooks.ready = Some(ready_tx);
+        let events = hooks.events.clone();
+        let requests = hooks.requests.clone();
+
+        let mut handle = tokio::spawn(server::run_server_with_updates(
+            config_rx,
+            shutdown_rx,
+            hooks,
+        ));
+
+        // 待ち受けを始める前に止まったら（bind の失敗など）そのエラーを返す
+        let local_addr = tokio::select! {
+            Ok(addr) = ready_rx => addr,
+            result = &mut handle => {
+                result.map_err(Error::shutdown)??;
+                return Err(Error::shutdown(anyhow::anyhow!(
+                    "Server stopped before it started listening"
+                )));
+            }
+        };
+
+        Ok(ServerHandle {
+            local_addr,
+            events,
+            requests,
+            shutdown_tx,
+            handle,
+        })
+    }
+}
+
+/// 起動したサーバー
+/// drop してもサーバーは止まらない。止めるには `shutdown` を呼ぶ
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    events: broadcast::Sender<ServerEvent>,
+    requests: mpsc::Sender<AdminRequest>,
+    shutdown_tx: broadcast::Sender<()>,
+    handle: JoinHandle<anyhow::Result<()>>,
+}
+
+impl ServerHandle {
+    /// 実際に待ち受けているアドレス（ポート 0 で起動した場合も実際のポートを返す）
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// 開いているトンネルの一覧（リモートポート順）
+    pub async fn tunnels(&self) -> Result<Vec<TunnelSummary>, Error> {
+        match self.request(AdminCommand::ListTunnels).await? {
+            AdminReply::Tunnels(tunnels) => Ok(tunnels),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// イベントを購読する。購読した後のイベントだけが届く
+    /// 読み遅れて取りこぼした場合は `RecvError::Lagged` になるので、`tunnels` で今の状態を取り直す
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+
+    /// サーバーを止める
+    /// 新しい接続の受け付けをやめ、開いているトンネルを閉じてポートを解放してから返る
+    /// クライアントには `shutting_down` を伝えるので、サーバーが戻れば再接続してくる
+    pub async fn shutdown(self) -> Result<(), Error> {
+        let _ = self.shutdown_tx.send(());
+        self.handle.await.map_err(Error::shutdown)??;
+        Ok(())
+    }
+
+    async fn request(&self, command: AdminCommand) -> Result<AdminReply, Error> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.requests
+            .send(AdminRequest { command, reply })
+            .await
+            .map_err(|_| Error::shutdown(anyhow::anyhow!("Server is not running")))?;
+        reply_rx
+            .await
+            .map_err(|_| Error::shutdown(anyhow::anyhow!("Server is not running")))
+    }
+}
+
+fn unexpected(reply: AdminReply) -> Error {
+    Error::Protocol(Arc::new(anyhow::anyhow!(
+        "Unexpected reply from server: {:?}",
+        reply
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ClientConfig;
+    use crate::tunnel::start_tunnel_with_config;
+    use std::net::Ipv4Addr;
+
+    #[tokio::test]
+    async fn test_server_handle() {
+        let server = ServerBuilder::new("127.0.0.1:0")
+            .port_ranges([PortRange::new(38100, 38199)])
+            .visitor_bind_ip(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .start()
+            .await
+            .unwrap();
+        let addr = server.local_addr();
+        assert_ne!(addr.port(), 0);
+        let mut events = server.subscribe();
+
+        let tunnel = start_tunnel_with_config(ClientConfig::new(addr.to_string(), 8080))
+            .await
+            .unwrap();
+        let port = tunnel.remote_port();
+        match events.recv().await.unwrap() {
+            ServerEvent::TunnelOpened(opened) => assert_eq!(opened.remote_port, port),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        let tunnels = server.tunnels().await.unwrap();
+        assert_eq!(tunnels.len(), 1);
+        assert_eq!(tunnels[0].local_port, 8080);
+
+        // 閉じ終わってから返るので、閉じたイベントはもう届いている
+        server.shutdown().await.unwrap();
+        match events.recv().await.unwrap() {
+            ServerEvent::TunnelClosed { tunnel, code } => {
+                assert_eq!(tunnel.remote_port, port);
+                assert_eq!(code, Some(ErrorCode::ShuttingDown));
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        tunnel.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_start_bind_error() {
+        let first = ServerBuilder::new("127.0.0.1:0").start().await.unwrap();
+        let err = ServerBuilder::new(first.local_addr().to_string())
+            .start()
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::Io(_)));
+        first.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// トンネルごとの訪問者数・転送量
+#[derive(Debug, Default)]
+pub struct TunnelStats {
+    active_visitors: AtomicU64,
+    /// 訪問者からクライアントへ送ったバイト数
+    bytes_in: AtomicU64,
+    /// クライアントから訪問者へ送ったバイト数
+    bytes_out: AtomicU64,
+}
+
+impl TunnelStats {
+    /// 接続中の訪問者として数える。返り値を捨てると数から外れる
+    pub fn visitor(self: &Arc<Self>) -> ActiveVisitor {
+        self.active_visitors.fetch_add(1, Ordering::Relaxed);
+        ActiveVisitor(self.clone())
+    }
+
+    /// 接続中の訪問者数
+    pub fn active_visitors(&self) -> u64 {
+        self.active_visitors.load(Ordering::Relaxed)
+    }
+
+    /// 訪問者からクライアントへ送ったバイト数
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    /// クライアントから訪問者へ送ったバイト数
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+}
+
+/// 接続中の訪問者（drop すると `TunnelStats` の数から外れる）
+pub struct ActiveVisitor(Arc<TunnelStats>);
+
+impl Drop for ActiveVisitor {
+    fn drop(&mut self) {
+        self.0.active_visitors.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 読み書きしたバイト数を `TunnelStats` に足していくデータチャネル
+/// データチャネルへの書き込みを `bytes_in`、読み込みを `bytes_out` として数える
+pub struct CountedStream<S> {
+    inner: S,
+    stats: Arc<TunnelStats>,
+}
+
+impl<S> CountedStream<S> {
+    pub fn new(inner: S, stats: Arc<TunnelStats>) -> Self {
+        Self { inner, stats }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        this.stats.bytes_out.fetch_add(read, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = &result {
+            this.stats
+                .bytes_in
+                .fetch_add(*written as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_counted_stream() {
+        let stats = Arc::new(TunnelStats::default());
+        let (data, mut client) = tokio::io::duplex(1024);
+        let mut data = CountedStream::new(data, stats.clone());
+
+        {
+            let _visitor = stats.visitor();
+            assert_eq!(stats.active_visitors(), 1);
+
+            data.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            client.read_exact(&mut buf).await.unwrap();
+            client.write_all(b"hi").await.unwrap();
+            let mut buf = [0u8; 2];
+            data.read_exact(&mut buf).await.unwrap();
+        }
+
+        assert_eq!(stats.active_visitors(), 0);
+        assert_eq!(stats.bytes_in(), 5);
+        assert_eq!(stats.bytes_out(), 2);
+    }
+}
@@ -0,0 +1,93 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::config::TransportConfig;
+
+mod tcp;
+pub use tcp::TcpTransport;
+
+#[cfg(feature = "native-tls")]
+mod native_tls;
+#[cfg(feature = "native-tls")]
+use self::native_tls as tls;
+#[cfg(all(feature = "rustls", not(feature = "native-tls")))]
+mod rustls;
+#[cfg(all(feature = "rustls", not(feature = "native-tls")))]
+use self::rustls as tls;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+pub use tls::TlsTransport;
+
+#[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
+mod websocket;
+#[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
+pub use websocket::WebsocketTransport;
+
+#[cfg(feature = "noise")]
+mod noise;
+#[cfg(feature = "noise")]
+pub use noise::{generate_keypair as generate_noise_keypair, NoiseTransport};
+
+/// コントロールチャネルとデータチャネルが使うトランスポート
+/// `Message::read_from`/`write_to` とデータのコピーは `Stream` 上で動く
+#[async_trait]
+pub trait Transport: Debug + Send + Sync + 'static {
+    type Acceptor: Send + Sync;
+    type RawStream: Send + Sync;
+    type Stream: 'static + AsyncRead + AsyncWrite + Unpin + Send + Sync + Debug;
+
+    /// 設定からトランスポートを作成
+    fn new(config: &TransportConfig) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// サーバー: 待ち受けを開始
+    async fn bind(&self, addr: &str) -> Result<Self::Acceptor>;
+
+    /// サーバー: 実際に待ち受けているアドレス（ポート 0 で bind した場合も実際のポートを返す）
+    fn local_addr(&self, acceptor: &Self::Acceptor) -> Result<SocketAddr>;
+
+    /// サーバー: 接続を1つ受け付ける
+    /// ハンドシェイクは accept ループを止めないよう `handshake` で別に行う
+    async fn accept(&self, acceptor: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)>;
+
+    /// サーバー: 受け付けた接続のハンドシェイク
+    async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream>;
+
+    /// クライアント: サーバーに接続
+    async fn connect(&self, addr: &str) -> Result<Self::Stream>;
+}
+
+/// 機能がコンパイルされていない場合のエラー
+#[allow(dead_code)]
+pub fn feature_not_compiled<T>(feature: &str) -> Result<T> {
+    Err(crate::error::Error::config(anyhow::anyhow!(
+        "The feature '{}' is not compiled in this binary. Please re-compile rathole",
+        feature
+    ))
+    .into())
+}
+
+/// `host:port` 形式のアドレスからホスト部分を取り出す（TLSのSNI・証明書検証、プロキシ用）
+fn host_of(addr: &str) -> &str {
+    let host = match addr.rfind(':') {
+        Some(idx) => &addr[..idx],
+        None => addr,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_of() {
+        assert_eq!(host_of("example.com:2333"), "example.com");
+        assert_eq!(host_of("127.0.0.1:2333"), "127.0.0.1");
+        assert_eq!(host_of("[::1]:2333"), "::1");
+        assert_eq!(host_of("example.com"), "example.com");
+    }
+}
@@ -0,0 +1,118 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::fs;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_native_tls::native_tls::{self, Certificate, Identity};
+use tokio_native_tls::{TlsAcceptor, TlsConnector, TlsStream};
+
+use super::{host_of, TcpTransport, Transport};
+use crate::config::{TlsConfig, TransportConfig};
+
+/// native-tls によるTLSトランスポート
+#[derive(Debug)]
+pub struct TlsTransport {
+    tcp: TcpTransport,
+    config: TlsConfig,
+    connector: TlsConnector,
+    acceptor: Option<TlsAcceptor>,
+}
+
+#[async_trait]
+impl Transport for TlsTransport {
+    type Acceptor = TcpListener;
+    type RawStream = TcpStream;
+    type Stream = TlsStream<TcpStream>;
+
+    fn new(config: &TransportConfig) -> Result<Self> {
+        let tcp = TcpTransport::new(config)?;
+        let config = config
+            .tls
+            .as_ref()
+            .context("Missing TLS configuration")?
+            .clone();
+
+        // クライアント: システムの証明書に加えて指定されたCAを信頼する
+        let mut connector = native_tls::TlsConnector::builder();
+        if let Some(path) = config.trusted_root.as_ref() {
+            let pem = fs::read(path)
+                .with_context(|| format!("Failed to read trusted root {}", path))?;
+            let cert = Certificate::from_pem(&pem)
+                .with_context(|| format!("Failed to parse trusted root {}", path))?;
+            connector.add_root_certificate(cert);
+        }
+        let connector = TlsConnector::from(connector.build()?);
+
+        // サーバー: PKCS#12 または PEM の証明書と秘密鍵
+        let acceptor = match load_identity(&config)? {
+            Some(identity) => Some(TlsAcceptor::from(native_tls::TlsAcceptor::new(identity)?)),
+            None => None,
+        };
+
+        Ok(TlsTransport {
+            tcp,
+            config,
+            connector,
+            acceptor,
+        })
+    }
+
+    async fn bind(&self, addr: &str) -> Result<Self::Acceptor> {
+        self.tcp.bind(addr).await
+    }
+
+    fn local_addr(&self, acceptor: &Self::Acceptor) -> Result<SocketAddr> {
+        self.tcp.local_addr(acceptor)
+    }
+
+    async fn accept(&self, acceptor: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
+        self.tcp.accept(acceptor).await
+    }
+
+    async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
+        let acceptor = self
+            .acceptor
+            .as_ref()
+            .context("TLS server certificate is not configured")?;
+        acceptor
+            .accept(conn)
+            .await
+            .context("TLS handshake failed")
+    }
+
+    async fn connect(&self, addr: &str) -> Result<Self::Stream> {
+        let stream = self.tcp.connect(addr).await?;
+        let hostname = self
+            .config
+            .hostname
+            .as_deref()
+            .unwrap_or_else(|| host_of(addr));
+        self.connector
+            .connect(hostname, stream)
+            .await
+            .with_context(|| format!("TLS handshake with {} failed", addr))
+    }
+}
+
+/// サーバー証明書を読み込む
+fn load_identity(config: &TlsConfig) -> Result<Option<Identity>> {
+    if let Some(path) = config.pkcs12.as_ref() {
+        let der = fs::read(path).with_context(|| format!("Failed to read PKCS#12 {}", path))?;
+        let password = config.pkcs12_password.as_deref().unwrap_or("");
+        let identity = Identity::from_pkcs12(&der, password)
+            .with_context(|| format!("Failed to load PKCS#12 {}", path))?;
+        return Ok(Some(identity));
+    }
+
+    match (config.cert.as_ref(), config.key.as_ref()) {
+        (Some(cert), Some(key)) => {
+            let cert_pem =
+                fs::read(cert).with_context(|| format!("Failed to read certificate {}", cert))?;
+            let key_pem = fs::read(key).with_context(|| format!("Failed to read key {}", key))?;
+            let identity = Identity::from_pkcs8(&cert_pem, &key_pem)
+                .context("Failed to load certificate and key (key must be PKCS#8 PEM)")?;
+            Ok(Some(identity))
+        }
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,183 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use snowstorm::{Builder, NoiseParams, NoiseStream};
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+
+use super::{TcpTransport, Transport};
+use crate::config::TransportConfig;
+
+/// Noise プロトコルによるトランスポート
+/// コントロールチャネル・データチャネルともに接続ごとにハンドシェイクする
+#[derive(Debug)]
+pub struct NoiseTransport {
+    tcp: TcpTransport,
+    params: NoiseParams,
+    local_private_key: Vec<u8>,
+    remote_public_key: Option<Vec<u8>>,
+}
+
+impl NoiseTransport {
+    fn builder(&self) -> Builder {
+        let builder = Builder::new(self.params.clone()).local_private_key(&self.local_private_key);
+        match &self.remote_public_key {
+            Some(key) => builder.remote_public_key(key),
+            None => builder,
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for NoiseTransport {
+    type Acceptor = TcpListener;
+    type RawStream = TcpStream;
+    type Stream = NoiseStream<TcpStream>;
+
+    fn new(config: &TransportConfig) -> Result<Self> {
+        let tcp = TcpTransport::new(config)?;
+        let config = config.noise.clone().unwrap_or_default();
+        let params: NoiseParams = config
+            .pattern
+            .parse()
+            .with_context(|| format!("Invalid noise pattern {}", config.pattern))?;
+
+        let remote_public_key = config
+            .remote_public_key
+            .as_deref()
+            .map(|key| decode_key(key, "remote public key"))
+            .transpose()?;
+
+        // NK のクライアントは静的鍵を使わないので、指定がなければ使い捨ての鍵を生成する
+        let local_private_key = match config.local_private_key.as_deref() {
+            Some(key) => decode_key(key, "local private key")?,
+            None => {
+                Builder::new(params.clone())
+                    .generate_keypair()
+                    .context("Failed to generate noise keypair")?
+                    .private
+            }
+        };
+
+        Ok(NoiseTransport {
+            tcp,
+            params,
+            local_private_key,
+            remote_public_key,
+        })
+    }
+
+    async fn bind(&self, addr: &str) -> Result<Self::Acceptor> {
+        self.tcp.bind(addr).await
+    }
+
+    fn local_addr(&self, acceptor: &Self::Acceptor) -> Result<SocketAddr> {
+        self.tcp.local_addr(acceptor)
+    }
+
+    async fn accept(&self, acceptor: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
+        self.tcp.accept(acceptor).await
+    }
+
+    async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
+        let state = self
+            .builder()
+            .build_responder()
+            .context("Failed to build noise responder")?;
+        NoiseStream::handshake(conn, state)
+            .await
+            .context("Noise handshake failed")
+    }
+
+    async fn connect(&self, addr: &str) -> Result<Self::Stream> {
+        let conn = self.tcp.connect(addr).await?;
+        let state = self
+            .builder()
+            .build_initiator()
+            .context("Failed to build noise initiator")?;
+        NoiseStream::handshake(conn, state)
+            .await
+            .with_context(|| format!("Noise handshake with {} failed", addr))
+    }
+}
+
+/// base64 の鍵をデコード
+fn decode_key(key: &str, what: &str) -> Result<Vec<u8>> {
+    base64::decode(key).with_context(|| format!("Failed to decode noise {} as base64", what))
+}
+
+/// 静的鍵ペアを生成して base64 で返す (秘密鍵, 公開鍵)
+pub fn generate_keypair(pattern: &str) -> Result<(String, String)> {
+    let params: NoiseParams = pattern
+        .parse()
+        .with_context(|| format!("Invalid noise pattern {}", pattern))?;
+    let keypair = Builder::new(params)
+        .generate_keypair()
+        .context("Failed to generate noise keypair")?;
+    Ok((base64::encode(keypair.private), base64::encode(keypair.public)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{NoiseConfig, TransportType, DEFAULT_NOISE_PATTERN};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn transport(local: Option<&str>, remote: Option<&str>, pattern: &str) -> NoiseTransport {
+        NoiseTransport::new(&TransportConfig {
+            transport_type: TransportType::Noise,
+            noise: Some(NoiseConfig {
+                pattern: pattern.to_string(),
+                local_private_key: local.map(str::to_string),
+                remote_public_key: remote.map(str::to_string),
+            }),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    async fn exchange(server: NoiseTransport, client: NoiseTransport) -> Result<Vec<u8>> {
+        let acceptor = server.bind("127.0.0.1:0").await?;
+        let addr = acceptor.local_addr()?.to_string();
+        let server_task = tokio::spawn(async move {
+            let (conn, _) = server.accept(&acceptor).await?;
+            let mut stream = server.handshake(conn).await?;
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await?;
+            stream.write_all(&buf).await?;
+            stream.flush().await?;
+            anyhow::Ok(())
+        });
+
+        let mut stream = client.connect(&addr).await?;
+        stream.write_all(b"hello").await?;
+        stream.flush().await?;
+        let mut buf = vec![0u8; 5];
+        stream.read_exact(&mut buf).await?;
+        server_task.await??;
+        Ok(buf)
+    }
+
+    #[tokio::test]
+    async fn test_nk_handshake() {
+        let (private, public) = generate_keypair(DEFAULT_NOISE_PATTERN).unwrap();
+        let server = transport(Some(&private), None, DEFAULT_NOISE_PATTERN);
+        let client = transport(None, Some(&public), DEFAULT_NOISE_PATTERN);
+        assert_eq!(exchange(server, client).await.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_kk_rejects_unknown_client() {
+        let pattern = "Noise_KK_25519_ChaChaPoly_BLAKE2s";
+        let (server_private, server_public) = generate_keypair(pattern).unwrap();
+        let (client_private, client_public) = generate_keypair(pattern).unwrap();
+        let (other_private, _) = generate_keypair(pattern).unwrap();
+
+        let server = transport(Some(&server_private), Some(&client_public), pattern);
+        let client = transport(Some(&client_private), Some(&server_public), pattern);
+        assert_eq!(exchange(server, client).await.unwrap(), b"hello");
+
+        let server = transport(Some(&server_private), Some(&client_public), pattern);
+        let client = transport(Some(&other_private), Some(&server_public), pattern);
+        assert!(exchange(server, client).await.is_err());
+    }
+}
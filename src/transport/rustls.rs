@@ -0,0 +1,172 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::fs;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::pki_types::{
+    CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName,
+};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+
+use super::{host_of, TcpTransport, Transport};
+use crate::config::{TlsConfig, TransportConfig};
+
+/// rustls によるTLSトランスポート
+pub struct TlsTransport {
+    tcp: TcpTransport,
+    config: TlsConfig,
+    connector: TlsConnector,
+    acceptor: Option<TlsAcceptor>,
+}
+
+impl std::fmt::Debug for TlsTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsTransport")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl Transport for TlsTransport {
+    type Acceptor = TcpListener;
+    type RawStream = TcpStream;
+    type Stream = TlsStream<TcpStream>;
+
+    fn new(config: &TransportConfig) -> Result<Self> {
+        let tcp = TcpTransport::new(config)?;
+        let config = config
+            .tls
+            .as_ref()
+            .context("Missing TLS configuration")?
+            .clone();
+
+        // クライアント: システムの証明書に加えて指定されたCAを信頼する
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_native_certs::load_native_certs()
+            .context("Failed to load system root certificates")?
+        {
+            // 読み込めない証明書があっても他の証明書は使う
+            let _ = roots.add(cert);
+        }
+        if let Some(path) = config.trusted_root.as_ref() {
+            for cert in load_certs(path)? {
+                roots
+                    .add(cert)
+                    .with_context(|| format!("Failed to add trusted root {}", path))?;
+            }
+        }
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(client_config));
+
+        // サーバー: PKCS#12 または PEM の証明書と秘密鍵
+        let acceptor = match load_identity(&config)? {
+            Some((certs, key)) => {
+                let server_config = ServerConfig::builder()
+                    .with_no_client_auth()
+                    .with_single_cert(certs, key)
+                    .context("Invalid server certificate or key")?;
+                Some(TlsAcceptor::from(Arc::new(server_config)))
+            }
+            None => None,
+        };
+
+        Ok(TlsTransport {
+            tcp,
+            config,
+            connector,
+            acceptor,
+        })
+    }
+
+    async fn bind(&self, addr: &str) -> Result<Self::Acceptor> {
+        self.tcp.bind(addr).await
+    }
+
+    fn local_addr(&self, acceptor: &Self::Acceptor) -> Result<SocketAddr> {
+        self.tcp.local_addr(acceptor)
+    }
+
+    async fn accept(&self, acceptor: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
+        self.tcp.accept(acceptor).await
+    }
+
+    async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
+        let acceptor = self
+            .acceptor
+            .as_ref()
+            .context("TLS server certificate is not configured")?;
+        let stream = acceptor
+            .accept(conn)
+            .await
+            .context("TLS handshake failed")?;
+        Ok(TlsStream::from(stream))
+    }
+
+    async fn connect(&self, addr: &str) -> Result<Self::Stream> {
+        let stream = self.tcp.connect(addr).await?;
+        let hostname = self
+            .config
+            .hostname
+            .as_deref()
+            .unwrap_or_else(|| host_of(addr));
+        let server_name = ServerName::try_from(hostname)
+            .with_context(|| format!("Invalid TLS hostname {}", hostname))?
+            .to_owned();
+        let stream = self
+            .connector
+            .connect(server_name, stream)
+            .await
+            .with_context(|| format!("TLS handshake with {} failed", addr))?;
+        Ok(TlsStream::from(stream))
+    }
+}
+
+/// PEMファイルから証明書チェーンを読み込む
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let file = fs::File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<std::io::Result<Vec<_>>>()
+        .with_context(|| format!("Failed to parse certificates in {}", path))
+}
+
+/// サーバー証明書と秘密鍵を読み込む
+fn load_identity(
+    config: &TlsConfig,
+) -> Result<Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>> {
+    if let Some(path) = config.pkcs12.as_ref() {
+        let der = fs::read(path).with_context(|| format!("Failed to read PKCS#12 {}", path))?;
+        let password = config.pkcs12_password.as_deref().unwrap_or("");
+        let pfx = p12::PFX::parse(&der)
+            .map_err(|e| anyhow::anyhow!("Failed to parse PKCS#12 {}: {:?}", path, e))?;
+        let certs = pfx
+            .cert_x509_bags(password)
+            .map_err(|e| anyhow::anyhow!("Failed to decrypt certificates in {}: {:?}", path, e))?;
+        let key = pfx
+            .key_bags(password)
+            .map_err(|e| anyhow::anyhow!("Failed to decrypt key in {}: {:?}", path, e))?
+            .into_iter()
+            .next()
+            .with_context(|| format!("No private key in {}", path))?;
+        let certs = certs.into_iter().map(CertificateDer::from).collect();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key));
+        return Ok(Some((certs, key)));
+    }
+
+    match (config.cert.as_ref(), config.key.as_ref()) {
+        (Some(cert), Some(key)) => {
+            let certs = load_certs(cert)?;
+            let file = fs::File::open(key).with_context(|| format!("Failed to open {}", key))?;
+            let key = rustls_pemfile::private_key(&mut BufReader::new(file))
+                .with_context(|| format!("Failed to parse key {}", key))?
+                .with_context(|| format!("No private key in {}", key))?;
+            Ok(Some((certs, key)))
+        }
+        _ => Ok(None),
+    }
+}
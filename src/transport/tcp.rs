@@ -0,0 +1,191 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::{TcpListener, TcpStream};
+use url::Url;
+
+use super::{host_of, Transport};
+use crate::config::TransportConfig;
+
+/// SOCKS5 プロキシのデフォルトポート
+const DEFAULT_SOCKS5_PORT: u16 = 1080;
+
+/// 平文のTCPトランスポート
+/// プロキシが設定されていれば、クライアントの接続はすべてプロキシを経由する
+#[derive(Debug)]
+pub struct TcpTransport {
+    proxy: Option<Url>,
+}
+
+#[async_trait]
+impl Transport for TcpTransport {
+    type Acceptor = TcpListener;
+    type RawStream = TcpStream;
+    type Stream = TcpStream;
+
+    fn new(config: &TransportConfig) -> Result<Self> {
+        if let Some(proxy) = config.proxy.as_ref() {
+            match proxy.scheme() {
+                "http" | "socks5" | "socks5h" => {}
+                scheme => anyhow::bail!(
+                    "Unsupported proxy scheme {} (expected http, socks5 or socks5h)",
+                    scheme
+                ),
+            }
+        }
+        Ok(TcpTransport {
+            proxy: config.proxy.clone(),
+        })
+    }
+
+    async fn bind(&self, addr: &str) -> Result<Self::Acceptor> {
+        TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to bind to {}", addr))
+    }
+
+    fn local_addr(&self, acceptor: &Self::Acceptor) -> Result<SocketAddr> {
+        acceptor
+            .local_addr()
+            .context("Failed to get the listening address")
+    }
+
+    async fn accept(&self, acceptor: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
+        let (stream, addr) = acceptor
+            .accept()
+            .await
+            .context("Failed to accept connection")?;
+        stream.set_nodelay(true)?;
+        Ok((stream, addr))
+    }
+
+    async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
+        Ok(conn)
+    }
+
+    async fn connect(&self, addr: &str) -> Result<Self::Stream> {
+        let stream = match self.proxy.as_ref() {
+            Some(proxy) => connect_via_proxy(proxy, addr).await?,
+            None => TcpStream::connect(addr)
+                .await
+                .with_context(|| format!("Failed to connect to {}", addr))?,
+        };
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+}
+
+/// プロキシ経由で接続（HTTP CONNECT / SOCKS5）
+async fn connect_via_proxy(proxy: &Url, addr: &str) -> Result<TcpStream> {
+    let host = host_of(addr);
+    let port: u16 = addr
+        .rsplit_once(':')
+        .and_then(|(_, port)| port.parse().ok())
+        .with_context(|| format!("Invalid server address {}", addr))?;
+
+    let proxy_host = proxy.host_str().context("Proxy URL has no host")?;
+    let proxy_port = match proxy.scheme() {
+        "http" => proxy.port_or_known_default(),
+        _ => Some(proxy.port().unwrap_or(DEFAULT_SOCKS5_PORT)),
+    }
+    .context("Proxy URL has no port")?;
+    let mut stream = TcpStream::connect((proxy_host, proxy_port))
+        .await
+        .with_context(|| format!("Failed to connect to proxy {}:{}", proxy_host, proxy_port))?;
+
+    let username = proxy.username();
+    let password = proxy.password().unwrap_or("");
+    match proxy.scheme() {
+        "http" => {
+            if username.is_empty() {
+                async_http_proxy::http_connect_tokio(&mut stream, host, port).await
+            } else {
+                async_http_proxy::http_connect_tokio_with_basic_auth(
+                    &mut stream,
+                    host,
+                    port,
+                    username,
+                    password,
+                )
+                .await
+            }
+            .with_context(|| format!("HTTP proxy failed to connect to {}", addr))?;
+        }
+        _ => {
+            let auth = (!username.is_empty()).then(|| async_socks5::Auth {
+                username: username.to_string(),
+                password: password.to_string(),
+            });
+            // ドメイン名はプロキシ側で解決させる
+            let target: async_socks5::AddrKind = match host.parse::<IpAddr>() {
+                Ok(ip) => (ip, port).into(),
+                Err(_) => (host, port).into(),
+            };
+            async_socks5::connect(&mut stream, target, auth)
+                .await
+                .with_context(|| format!("SOCKS5 proxy failed to connect to {}", addr))?;
+        }
+    }
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    /// CONNECT だけを処理する最小限のHTTPプロキシ
+    async fn run_http_proxy(listener: TcpListener) {
+        let (conn, _) = listener.accept().await.unwrap();
+        let mut conn = BufReader::new(conn);
+        let mut request_line = String::new();
+        conn.read_line(&mut request_line).await.unwrap();
+        loop {
+            let mut line = String::new();
+            conn.read_line(&mut line).await.unwrap();
+            if line == "\r\n" {
+                break;
+            }
+        }
+        let target = request_line.split_whitespace().nth(1).unwrap().to_string();
+        let mut upstream = TcpStream::connect(target).await.unwrap();
+        let mut conn = conn.into_inner();
+        conn.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .unwrap();
+        let _ = tokio::io::copy_bidirectional(&mut conn, &mut upstream).await;
+    }
+
+    #[tokio::test]
+    async fn test_connect_via_http_proxy() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut conn, _) = target.accept().await.unwrap();
+            conn.write_all(b"hello").await.unwrap();
+        });
+
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_url = format!("http://{}", proxy.local_addr().unwrap());
+        tokio::spawn(run_http_proxy(proxy));
+
+        let transport = TcpTransport::new(&TransportConfig {
+            proxy: Some(Url::parse(&proxy_url).unwrap()),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut stream = transport.connect(&target_addr).await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn test_unsupported_proxy_scheme() {
+        let config = TransportConfig {
+            proxy: Some(Url::parse("ftp://proxy:21").unwrap()),
+            ..Default::default()
+        };
+        assert!(TcpTransport::new(&config).is_err());
+    }
+}
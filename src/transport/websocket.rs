@@ -0,0 +1,293 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_core::Stream;
+use futures_sink::Sink;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context as TaskContext, Poll};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{accept_async, client_async, WebSocketStream};
+use tokio_util::io::StreamReader;
+use url::Url;
+
+use super::{TcpTransport, TlsTransport, Transport};
+use crate::config::TransportConfig;
+
+type TlsStream = <TlsTransport as Transport>::Stream;
+
+/// WebSocket トランスポート
+/// コントロールチャネルのメッセージもデータもバイナリメッセージとして送る
+#[derive(Debug)]
+pub struct WebsocketTransport {
+    tcp: TcpTransport,
+    tls: TlsTransport,
+    /// サーバー: wss:// として待ち受ける
+    server_tls: bool,
+}
+
+#[async_trait]
+impl Transport for WebsocketTransport {
+    type Acceptor = TcpListener;
+    type RawStream = TcpStream;
+    type Stream = WebsocketTunnel;
+
+    fn new(config: &TransportConfig) -> Result<Self> {
+        let tcp = TcpTransport::new(config)?;
+        // wss:// を使うかは接続先の URL で決まるので、TLS設定は省略されていてもよい
+        let mut tls_config = config.clone();
+        tls_config.tls.get_or_insert_with(Default::default);
+        let tls = TlsTransport::new(&tls_config)?;
+        let server_tls = config.websocket.as_ref().map_or(false, |ws| ws.tls);
+        Ok(WebsocketTransport {
+            tcp,
+            tls,
+            server_tls,
+        })
+    }
+
+    async fn bind(&self, addr: &str) -> Result<Self::Acceptor> {
+        self.tcp.bind(addr).await
+    }
+
+    fn local_addr(&self, acceptor: &Self::Acceptor) -> Result<SocketAddr> {
+        self.tcp.local_addr(acceptor)
+    }
+
+    async fn accept(&self, acceptor: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
+        self.tcp.accept(acceptor).await
+    }
+
+    async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
+        let stream = if self.server_tls {
+            MaybeTlsStream::Tls(self.tls.handshake(conn).await?)
+        } else {
+            MaybeTlsStream::Plain(conn)
+        };
+        let ws = accept_async(stream)
+            .await
+            .context("WebSocket handshake failed")?;
+        Ok(WebsocketTunnel::new(ws))
+    }
+
+    async fn connect(&self, addr: &str) -> Result<Self::Stream> {
+        let url = websocket_url(addr)?;
+        let host = url.host_str().context("WebSocket URL has no host")?;
+        let port = url
+            .port_or_known_default()
+            .context("WebSocket URL has no port")?;
+        let host_port = format!("{}:{}", host, port);
+
+        let stream = if url.scheme() == "wss" {
+            MaybeTlsStream::Tls(self.tls.connect(&host_port).await?)
+        } else {
+            MaybeTlsStream::Plain(self.tcp.connect(&host_port).await?)
+        };
+        let (ws, _) = client_async(url.as_str(), stream)
+            .await
+            .with_context(|| format!("WebSocket handshake with {} failed", url))?;
+        Ok(WebsocketTunnel::new(ws))
+    }
+}
+
+/// 接続先を WebSocket の URL にする。スキームがなければ ws:// とみなす
+fn websocket_url(addr: &str) -> Result<Url> {
+    let url = if addr.contains("://") {
+        Url::parse(addr)
+    } else {
+        Url::parse(&format!("ws://{}/", addr))
+    }
+    .with_context(|| format!("Invalid WebSocket URL {}", addr))?;
+    match url.scheme() {
+        "ws" | "wss" => Ok(url),
+        scheme => anyhow::bail!("Unsupported WebSocket scheme {} in {}", scheme, addr),
+    }
+}
+
+/// 平文またはTLSのストリーム
+#[derive(Debug)]
+enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(TlsStream),
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// WebSocket のバイナリメッセージをバイト列のストリームとして読む
+#[derive(Debug)]
+struct StreamWrapper {
+    inner: WebSocketStream<MaybeTlsStream>,
+}
+
+impl Stream for StreamWrapper {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        let inner = &mut self.get_mut().inner;
+        loop {
+            match ready!(Pin::new(&mut *inner).poll_next(cx)) {
+                Some(Ok(WsMessage::Binary(data))) => return Poll::Ready(Some(Ok(Bytes::from(data)))),
+                Some(Ok(WsMessage::Close(_))) | None => return Poll::Ready(None),
+                // Ping への応答は tungstenite が行うので、制御メッセージは読み飛ばす
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Poll::Ready(Some(Err(io::Error::new(io::ErrorKind::Other, e)))),
+            }
+        }
+    }
+}
+
+/// WebSocket 上のバイトストリーム
+#[derive(Debug)]
+pub struct WebsocketTunnel {
+    inner: StreamReader<StreamWrapper, Bytes>,
+}
+
+impl WebsocketTunnel {
+    fn new(ws: WebSocketStream<MaybeTlsStream>) -> Self {
+        WebsocketTunnel {
+            inner: StreamReader::new(StreamWrapper { inner: ws }),
+        }
+    }
+
+    fn sink(self: Pin<&mut Self>) -> Pin<&mut WebSocketStream<MaybeTlsStream>> {
+        Pin::new(&mut self.get_mut().inner.get_mut().inner)
+    }
+}
+
+impl AsyncRead for WebsocketTunnel {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncBufRead for WebsocketTunnel {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().inner).poll_fill_buf(cx)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.get_mut().inner).consume(amt)
+    }
+}
+
+impl AsyncWrite for WebsocketTunnel {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.as_mut().sink().poll_ready(cx)).map_err(ws_error)?;
+        self.sink()
+            .start_send(WsMessage::Binary(buf.to_vec()))
+            .map_err(ws_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        self.sink().poll_flush(cx).map_err(ws_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        self.sink().poll_close(cx).map_err(ws_error)
+    }
+}
+
+fn ws_error(e: tokio_tungstenite::tungstenite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TransportType;
+    use crate::protocol::Message;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_websocket_url() {
+        assert_eq!(
+            websocket_url("example.com:2333").unwrap().as_str(),
+            "ws://example.com:2333/"
+        );
+        let url = websocket_url("wss://example.com/tunnel").unwrap();
+        assert_eq!(url.port_or_known_default(), Some(443));
+        assert_eq!(url.path(), "/tunnel");
+        assert!(websocket_url("http://example.com").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_message_and_data_roundtrip() {
+        let config = TransportConfig {
+            transport_type: TransportType::Websocket,
+            ..Default::default()
+        };
+        let server = WebsocketTransport::new(&config).unwrap();
+        let client = WebsocketTransport::new(&config).unwrap();
+        let acceptor = server.bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("ws://{}/rathole", acceptor.local_addr().unwrap());
+
+        let server_task = tokio::spawn(async move {
+            let (conn, _) = server.accept(&acceptor).await.unwrap();
+            let mut stream = server.handshake(conn).await.unwrap();
+            let msg = Message::read_from(&mut stream).await.unwrap();
+            let mut data = vec![0u8; 100_000];
+            stream.read_exact(&mut data).await.unwrap();
+            (msg, data)
+        });
+
+        let mut stream = client.connect(&addr).await.unwrap();
+        Message::Heartbeat.write_to(&mut stream).await.unwrap();
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        stream.write_all(&data).await.unwrap();
+        stream.flush().await.unwrap();
+
+        let (msg, received) = server_task.await.unwrap();
+        assert!(matches!(msg, Message::Heartbeat));
+        assert_eq!(received, data);
+    }
+}
@@ -1,22 +1,98 @@
 use anyhow::Result;
-use tokio::sync::broadcast;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
 use tokio::task::JoinHandle;
+use url::Url;
 
-use crate::client;
+use crate::client::{self, ServiceStatus};
+use crate::config::{
+    ClientConfig, NoiseConfig, RetryConfig, ServiceConfig, ServiceProtocol, TimeoutConfig,
+    TlsConfig, TransportConfig, TransportType,
+};
+use crate::error::{Error, TunnelError};
+
+/// トンネルで公開しているサービスの状態
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceInfo {
+    /// トンネル名
+    pub name: Option<String>,
+    /// 転送先のアドレス (host:port)
+    pub local_addr: String,
+    /// 割り当てられたリモートポート（まだ割り当てられていなければ `None`）
+    pub remote_port: Option<u16>,
+    /// コントロールチャネルが接続中かどうか
+    pub connected: bool,
+    /// 最後の接続エラー（再接続を試みている間の理由）
+    pub last_error: Option<String>,
+    /// サーバーが拒否した理由。再試行できない場合、このサービスは再接続しない
+    pub rejection: Option<TunnelError>,
+    /// 再接続をあきらめた（再試行できない拒否・`RetryConfig::max_elapsed_secs` の超過）
+    /// このサービスは `last_error` のまま止まっている
+    pub gave_up: bool,
+}
+
+/// 購読者が読み遅れても残しておくイベントの数
+const EVENT_CAPACITY: usize = 64;
+
+/// サービスで起きたこと（`Tunnel::events` で受け取る）
+#[derive(Debug, Clone)]
+pub struct TunnelEvent {
+    /// イベントが起きたサービス（`Tunnel::services` の中での位置）
+    pub service: usize,
+    pub kind: TunnelEventKind,
+}
+
+/// イベントの種類
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum TunnelEventKind {
+    /// コントロールチャネルがつながり、リモートポートが割り当てられた
+    Connected { port: u16 },
+    /// 再接続で前回と違うリモートポートが割り当てられた（続けて `Connected` が届く）
+    PortChanged { old: u16, new: u16 },
+    /// つながっていたコントロールチャネルが切れた（サーバーが正常に閉じた場合は `error` が `None`）
+    Disconnected { error: Option<Error> },
+    /// `delay` 待ってから再接続する。`attempt` は最後に接続できてから何回目か
+    Reconnecting { attempt: u32, delay: Duration },
+    /// 再接続をあきらめた（再試行できないエラー・`RetryConfig::max_elapsed_secs` の超過）
+    GaveUp { error: Error },
+    /// 訪問者の接続をローカルサービスにつないだ
+    VisitorOpened,
+    /// 訪問者の接続が閉じた
+    VisitorClosed,
+}
+
+/// トンネル全体の状態（`Tunnel::state` で受け取る）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TunnelState {
+    /// 動いているサービスがすべてつながっている
+    Connected,
+    /// 再接続を待っている・再接続中のサービスがある
+    Reconnecting,
+    /// すべてのサービスが止まった（再接続をあきらめた・シャットダウン）
+    Stopped,
+}
 
 /// 確立されたトンネル
+/// 複数のサービスを公開している場合、サービスごとに独立して再接続する
 pub struct Tunnel {
     remote_addr: String,
-    local_port: u16,
-    assigned_port: u16,
+    services: Vec<ServiceConfig>,
+    statuses: Vec<watch::Receiver<ServiceStatus>>,
+    events: broadcast::Sender<TunnelEvent>,
+    state: watch::Receiver<TunnelState>,
     shutdown_tx: broadcast::Sender<()>,
     handle: JoinHandle<Result<()>>,
 }
 
 impl Tunnel {
-    /// 割り当てられたリモートポートを取得
+    /// 最初のサービスに現在割り当てられているリモートポートを取得
+    /// 再接続で別のポートが割り当てられた場合は新しいポートを返す
     pub fn remote_port(&self) -> u16 {
-        self.assigned_port
+        self.statuses[0].borrow().remote_port
     }
 
     /// リモートアドレスを取得
@@ -24,15 +100,55 @@ impl Tunnel {
         &self.remote_addr
     }
 
-    /// ローカルポートを取得
+    /// 最初のサービスのローカルポートを取得
     pub fn local_port(&self) -> u16 {
-        self.local_port
+        self.services[0].local_port
+    }
+
+    /// すべてのサービスの状態を取得（設定と同じ順）
+    pub fn services(&self) -> Vec<ServiceInfo> {
+        self.services
+            .iter()
+            .zip(&self.statuses)
+            .map(|(service, status)| {
+                let status = status.borrow().clone();
+                ServiceInfo {
+                    name: service.name.clone(),
+                    local_addr: service.local_addr(),
+                    remote_port: (status.remote_port != 0).then_some(status.remote_port),
+                    connected: status.connected,
+                    last_error: status.last_error,
+                    rejection: status.error.as_ref().and_then(Error::rejection).cloned(),
+                    gave_up: status.gave_up,
+                }
+            })
+            .collect()
+    }
+
+    /// 名前を指定してサービスの状態を取得
+    pub fn service(&self, name: &str) -> Option<ServiceInfo> {
+        let index = self
+            .services
+            .iter()
+            .position(|service| service.name.as_deref() == Some(name))?;
+        self.services().into_iter().nth(index)
+    }
+
+    /// 接続・切断・再接続・訪問者の出入りを購読する。購読した後のイベントだけが届く
+    /// 読み遅れて取りこぼした場合は `RecvError::Lagged` になるので、`services` で今の状態を取り直す
+    pub fn events(&self) -> broadcast::Receiver<TunnelEvent> {
+        self.events.subscribe()
+    }
+
+    /// トンネル全体の状態。`changed().await` で変わるのを待てる
+    pub fn state(&self) -> watch::Receiver<TunnelState> {
+        self.state.clone()
     }
 
     /// トンネルをシャットダウン
-    pub async fn shutdown(self) -> Result<()> {
+    pub async fn shutdown(self) -> Result<(), Error> {
         let _ = self.shutdown_tx.send(());
-        self.handle.await??;
+        self.handle.await.map_err(Error::shutdown)??;
         Ok(())
     }
 }
@@ -45,6 +161,9 @@ impl Tunnel {
 ///
 /// # 戻り値
 /// 確立されたトンネル。サーバーから割り当てられたポート番号を含む。
+/// サーバーにつながらない間は `ClientConfig::retry` に従って再接続を続ける。
+/// 再試行できない拒否（認証の失敗など）や `RetryConfig::max_elapsed_secs` の超過で
+/// あきらめた場合は [`Error`] を返す（`is_transient` でやり直す価値があるか分かる）。
 ///
 /// # 例
 /// ```no_run
@@ -65,30 +184,559 @@ impl Tunnel {
 pub async fn start_tunnel(
     remote_addr: impl Into<String>,
     local_port: u16,
-) -> Result<Tunnel> {
-    let remote_addr = remote_addr.into();
-    let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+) -> Result<Tunnel, Error> {
+    start_tunnel_with_config(ClientConfig::new(remote_addr, local_port)).await
+}
 
-    // クライアントを起動してポート番号を取得
-    let assigned_port = client::connect_and_get_port(
-        remote_addr.clone(),
-        local_port,
-        shutdown_rx.resubscribe(),
-    )
-    .await?;
+/// 設定を指定してトンネルを開始
+/// どれかのサービスにポートが割り当てられ、他のサービスも最初の接続を終えるまで待つ
+/// つながらない間は再接続を続け、すべてのサービスが再接続をあきらめたらエラーを返す
+/// 待たずにあきらめたい場合は `max_elapsed_secs` を指定するか、`tokio::time::timeout` で包む
+///
+/// # 例
+/// ```no_run
+/// use rathole::{start_tunnel_with_config, ClientConfig, ServiceConfig};
+///
+/// #[tokio::main]
+/// async fn main() -> anyhow::Result<()> {
+///     let mut config = ClientConfig::new("myserver.com:2333", 8080);
+///     config.token = Some("secret".to_string());
+///
+///     // SSH も同じプロセスで公開する
+///     let mut ssh = ServiceConfig::new(22);
+///     ssh.name = Some("ssh".to_string());
+///     config.services.push(ssh);
+///
+///     let tunnel = start_tunnel_with_config(config).await?;
+///     for service in tunnel.services() {
+///         println!("{} -> {:?}", service.local_addr, service.remote_port);
+///     }
+///     Ok(())
+/// }
+/// ```
+pub async fn start_tunnel_with_config(config: ClientConfig) -> Result<Tunnel, Error> {
+    config.validate().map_err(Error::config)?;
+    let remote_addr = config.remote_addr.clone();
+    let services = config.services.clone();
+    let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+    let (events, _) = broadcast::channel(EVENT_CAPACITY);
+    let (status_txs, statuses): (Vec<_>, Vec<_>) = services
+        .iter()
+        .map(|_| watch::channel(ServiceStatus::default()))
+        .unzip();
 
     // バックグラウンドでクライアントを実行
-    let remote_addr_clone = remote_addr.clone();
-    let shutdown_rx_clone = shutdown_rx.resubscribe();
-    let handle = tokio::spawn(async move {
-        client::run_client(remote_addr_clone, local_port, shutdown_rx_clone).await
+    // サービスごとのコントロールチャネルが割り当てられたポートを通知する
+    // 接続・失敗のたびにイベントが届くので、それを合図に状態を見直す
+    let mut changes = events.subscribe();
+    let events_tx = events.clone();
+    let mut handle = tokio::spawn(async move {
+        client::run_client(config, status_txs, events_tx, shutdown_rx).await
     });
 
+    // 最初の接続が終わるのを待つ。失敗したサービスはバックグラウンドで再接続を続ける
+    // この future が drop されると `shutdown_tx` も drop され、クライアントは止まる
+    let wait_started = async {
+        loop {
+            if let Some(started) = startup_result(&statuses) {
+                return started;
+            }
+            // 読み遅れ（Lagged）でも状態を見直せばよい
+            let _ = changes.recv().await;
+        }
+    };
+    let started = tokio::select! {
+        started = wait_started => started,
+        result = &mut handle => {
+            result.map_err(Error::shutdown)??;
+            false
+        }
+    };
+
+    // すべてのサービスが再接続をあきらめたか、クライアントが止まったら失敗とする
+    // 最初に失敗したサービスのエラーを返す
+    if !started {
+        let _ = shutdown_tx.send(());
+        let error = statuses
+            .iter()
+            .find_map(|status| status.borrow().error.clone())
+            .unwrap_or_else(|| {
+                Error::shutdown(anyhow::anyhow!("Client stopped before a port was assigned"))
+            });
+        return Err(error);
+    }
+
+    let state = watch_state(&statuses);
     Ok(Tunnel {
         remote_addr,
-        local_port,
-        assigned_port,
+        services,
+        statuses,
+        events,
+        state,
         shutdown_tx,
         handle,
     })
 }
+
+/// 最初の接続を待ち終えたか
+/// どれかのサービスにポートが割り当てられ、他のサービスも最初の接続を終えていれば `Some(true)`、
+/// すべてのサービスが再接続をあきらめていれば `Some(false)`、まだ待つなら `None`
+fn startup_result(statuses: &[watch::Receiver<ServiceStatus>]) -> Option<bool> {
+    let statuses: Vec<ServiceStatus> = statuses
+        .iter()
+        .map(|status| status.borrow().clone())
+        .collect();
+    if statuses.iter().all(|status| status.gave_up) {
+        return Some(false);
+    }
+    let assigned = statuses.iter().any(|status| status.remote_port != 0);
+    let attempted = statuses
+        .iter()
+        .all(|status| status.remote_port != 0 || status.last_error.is_some());
+    (assigned && attempted).then_some(true)
+}
+
+/// サービスごとの状態が変わるたびにトンネル全体の状態を求め直す
+/// サービスが止まると状態の送信側が drop されるので、受信側から止まったことが分かる
+fn watch_state(statuses: &[watch::Receiver<ServiceStatus>]) -> watch::Receiver<TunnelState> {
+    let (state_tx, state_rx) = watch::channel(tunnel_state(statuses));
+    let state_tx = Arc::new(state_tx);
+    for status in statuses {
+        let mut status = status.clone();
+        let statuses = statuses.to_vec();
+        let state_tx = state_tx.clone();
+        tokio::spawn(async move {
+            loop {
+                let stopped = status.changed().await.is_err();
+                state_tx.send_if_modified(|state| {
+                    let new_state = tunnel_state(&statuses);
+                    let modified = *state != new_state;
+                    *state = new_state;
+                    modified
+                });
+                if stopped {
+                    break;
+                }
+            }
+        });
+    }
+    state_rx
+}
+
+fn tunnel_state(statuses: &[watch::Receiver<ServiceStatus>]) -> TunnelState {
+    // 送信側が drop されていればそのサービスは止まっている
+    let mut running = statuses
+        .iter()
+        .filter(|status| status.has_changed().is_ok())
+        .peekable();
+    if running.peek().is_none() {
+        TunnelState::Stopped
+    } else if running.all(|status| status.borrow().connected) {
+        TunnelState::Connected
+    } else {
+        TunnelState::Reconnecting
+    }
+}
+
+/// オプションを指定してトンネルを開始する
+/// `local_*`・`remote_port`・`name`・`protocol` は最初のサービスに、それ以外はクライアント全体に効く
+///
+/// # 例
+/// ```no_run
+/// use rathole::{NoiseConfig, RetryConfig, TunnelBuilder};
+///
+/// #[tokio::main]
+/// async fn main() -> anyhow::Result<()> {
+///     let tunnel = TunnelBuilder::new("myserver.com:2333")
+///         .local_addr("192.168.1.20:8080")
+///         .remote_port(35100)
+///         .name("web")
+///         .token("secret")
+///         .noise(NoiseConfig {
+///             remote_public_key: Some("server-public-key".to_string()),
+///             ..Default::default()
+///         })
+///         .retry_policy(RetryConfig {
+///             max_elapsed_secs: Some(600),
+///             ..Default::default()
+///         })
+///         .start()
+///         .await?;
+///     println!("Remote port: {}", tunnel.remote_port());
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct TunnelBuilder {
+    config: ClientConfig,
+    /// `local_addr` に渡された書式の誤り（`start` で返す）
+    invalid_local_addr: Option<String>,
+}
+
+impl TunnelBuilder {
+    /// サーバーアドレス (例: "myserver.com:2333") を指定して作成
+    /// ローカルポートは `local_port` か `local_addr` で指定する
+    pub fn new(remote_addr: impl Into<String>) -> Self {
+        Self::from_config(ClientConfig::new(remote_addr, 0))
+    }
+
+    /// 設定から作成（設定ファイルは `ClientConfig::from_file` で読み込む）
+    pub fn from_config(config: ClientConfig) -> Self {
+        Self {
+            config,
+            invalid_local_addr: None,
+        }
+    }
+
+    fn service_mut(&mut self) -> &mut ServiceConfig {
+        if self.config.services.is_empty() {
+            self.config.services.push(ServiceConfig::new(0));
+        }
+        &mut self.config.services[0]
+    }
+
+    /// 転送先のポート
+    pub fn local_port(mut self, port: u16) -> Self {
+        self.service_mut().local_port = port;
+        self
+    }
+
+    /// 転送先のホスト（IPアドレスまたはホスト名）
+    pub fn local_host(mut self, host: impl Into<String>) -> Self {
+        self.service_mut().local_host = host.into();
+        self
+    }
+
+    /// 転送先のアドレス (例: "127.0.0.1:8080", "db.internal:5432", "[::1]:22")
+    pub fn local_addr(mut self, addr: impl AsRef<str>) -> Self {
+        let addr = addr.as_ref();
+        match addr.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() => match port.parse() {
+                Ok(port) => {
+                    let service = self.service_mut();
+                    service.local_host = host.to_string();
+                    service.local_port = port;
+                }
+                Err(_) => self.invalid_local_addr = Some(addr.to_string()),
+            },
+            _ => self.invalid_local_addr = Some(addr.to_string()),
+        }
+        self
+    }
+
+    /// 転送先の Unix ドメインソケット
+    pub fn local_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.service_mut().local_socket = Some(path.into());
+        self
+    }
+
+    /// 希望するリモートポート（使えない場合は別のポートが割り当てられる）
+    pub fn remote_port(mut self, port: u16) -> Self {
+        self.service_mut().remote_port = Some(port);
+        self
+    }
+
+    /// トンネル名。サーバーは名前ごとに前回のポートを覚えている
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.service_mut().name = Some(name.into());
+        self
+    }
+
+    /// プロトコル (tcp / udp)
+    pub fn protocol(mut self, protocol: ServiceProtocol) -> Self {
+        self.service_mut().protocol = protocol;
+        self
+    }
+
+    /// 同じコントロールチャネルの設定で公開するサービスを追加する
+    pub fn service(mut self, service: ServiceConfig) -> Self {
+        self.config.services.push(service);
+        self
+    }
+
+    /// 認証トークン
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.config.token = Some(token.into());
+        self
+    }
+
+    /// トランスポートの設定をまとめて指定する（プロキシも含めて置き換える）
+    pub fn transport(mut self, transport: TransportConfig) -> Self {
+        self.config.transport = transport;
+        self
+    }
+
+    /// TLS で接続する
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.config.transport.transport_type = TransportType::Tls;
+        self.config.transport.tls = Some(tls);
+        self
+    }
+
+    /// Noise で接続する
+    pub fn noise(mut self, noise: NoiseConfig) -> Self {
+        self.config.transport.transport_type = TransportType::Noise;
+        self.config.transport.noise = Some(noise);
+        self
+    }
+
+    /// サーバーへの接続に使うプロキシ (http:// / socks5://)
+    /// 指定しなければ環境変数 `ALL_PROXY` / `HTTPS_PROXY` のものを使う
+    pub fn proxy(mut self, proxy: Url) -> Self {
+        self.config.transport.proxy = Some(proxy);
+        self
+    }
+
+    /// 環境変数のプロキシも使わずに直接接続する
+    pub fn no_proxy(mut self) -> Self {
+        self.config.transport.proxy = None;
+        self
+    }
+
+    /// 訪問者ごとに接続を張らず、1本の接続にストリームを多重化する
+    pub fn multiplex(mut self, multiplex: bool) -> Self {
+        self.config.multiplex = multiplex;
+        self
+    }
+
+    /// 訪問者を待つデータチャネルを前もって張っておく数
+    pub fn pool_size(mut self, pool_size: u16) -> Self {
+        self.config.pool_size = pool_size;
+        self
+    }
+
+    /// 切断・接続失敗のあとの再接続の間隔
+    pub fn retry_policy(mut self, retry: RetryConfig) -> Self {
+        self.config.retry = retry;
+        self
+    }
+
+    /// 接続・応答・ハートビートの時間
+    pub fn timeouts(mut self, timeouts: TimeoutConfig) -> Self {
+        self.config.timeouts = timeouts;
+        self
+    }
+
+    /// ここまでの設定
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    /// トンネルを開始する（`start_tunnel_with_config` と同じく最初の接続が終わるまで待つ）
+    pub async fn start(self) -> Result<Tunnel, Error> {
+        if let Some(addr) = self.invalid_local_addr {
+            return Err(Error::config(anyhow::anyhow!(
+                "Invalid local address {} (expected host:port)",
+                addr
+            )));
+        }
+        start_tunnel_with_config(self.config).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder() {
+        let builder = TunnelBuilder::new("myserver.com:2333")
+            .local_addr("[::1]:22")
+            .remote_port(35100)
+            .name("ssh")
+            .token("secret")
+            .noise(NoiseConfig::default())
+            .no_proxy()
+            .service(ServiceConfig::new(8080));
+        let config = builder.config();
+        config.validate().unwrap();
+        assert_eq!(config.remote_addr, "myserver.com:2333");
+        assert_eq!(config.token.as_deref(), Some("secret"));
+        assert_eq!(config.transport.transport_type, TransportType::Noise);
+        assert!(config.transport.proxy.is_none());
+        assert_eq!(config.services.len(), 2);
+        assert_eq!(config.services[0].local_addr(), "[::1]:22");
+        assert_eq!(config.services[0].remote_port, Some(35100));
+        assert_eq!(config.services[0].name.as_deref(), Some("ssh"));
+        assert_eq!(config.services[1].local_port, 8080);
+    }
+
+    #[tokio::test]
+    async fn test_builder_errors() {
+        // ポートのないアドレス・ローカルポートの指定漏れは接続する前にエラー
+        let err = TunnelBuilder::new("127.0.0.1:1")
+            .local_addr("localhost")
+            .start()
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::Config(_)));
+
+        let err = TunnelBuilder::new("127.0.0.1:1").start().await.err().unwrap();
+        assert!(matches!(err, Error::Config(_)));
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        use crate::config::PortRange;
+        use crate::protocol::ErrorCode;
+        use crate::server_handle::ServerBuilder;
+        use std::net::{IpAddr, Ipv4Addr};
+        use tokio::net::{TcpListener, TcpStream};
+
+        let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = ServerBuilder::new("127.0.0.1:0")
+            .port_ranges([PortRange::new(38200, 38299)])
+            .visitor_bind_ip(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .start()
+            .await
+            .unwrap();
+        let tunnel = TunnelBuilder::new(server.local_addr().to_string())
+            .local_port(local.local_addr().unwrap().port())
+            .no_proxy()
+            .start()
+            .await
+            .unwrap();
+        let mut events = tunnel.events();
+        let mut state = tunnel.state();
+        assert_eq!(*state.borrow(), TunnelState::Connected);
+
+        // 訪問者をローカルサービスにつなぎ、切断する
+        let visitor = TcpStream::connect(("127.0.0.1", tunnel.remote_port()))
+            .await
+            .unwrap();
+        let (_conn, _) = local.accept().await.unwrap();
+        let event = events.recv().await.unwrap();
+        assert_eq!(event.service, 0);
+        assert!(matches!(event.kind, TunnelEventKind::VisitorOpened));
+        drop(visitor);
+        let event = events.recv().await.unwrap();
+        assert!(matches!(event.kind, TunnelEventKind::VisitorClosed));
+
+        // サーバーが止まると切断され、再接続を待つ
+        server.shutdown().await.unwrap();
+        match events.recv().await.unwrap().kind {
+            TunnelEventKind::Disconnected { error: Some(error) } => {
+                assert_eq!(error.rejection().unwrap().code, ErrorCode::ShuttingDown);
+            }
+            kind => panic!("Unexpected event: {:?}", kind),
+        }
+        match events.recv().await.unwrap().kind {
+            TunnelEventKind::Reconnecting { attempt, .. } => assert_eq!(attempt, 1),
+            kind => panic!("Unexpected event: {:?}", kind),
+        }
+        state.changed().await.unwrap();
+        assert_eq!(*state.borrow_and_update(), TunnelState::Reconnecting);
+
+        tunnel.shutdown().await.unwrap();
+        state.changed().await.unwrap();
+        assert_eq!(*state.borrow(), TunnelState::Stopped);
+    }
+
+    #[tokio::test]
+    async fn test_waits_for_server() {
+        use crate::config::PortRange;
+        use crate::server_handle::ServerBuilder;
+        use std::net::{IpAddr, Ipv4Addr};
+
+        // サーバーがまだ起動していなくても、起動するまで再接続を続ける
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let start = tokio::spawn(
+            TunnelBuilder::new(addr.to_string())
+                .local_port(8080)
+                .no_proxy()
+                .retry_policy(RetryConfig {
+                    initial_interval_ms: 50,
+                    max_interval_ms: 100,
+                    ..Default::default()
+                })
+                .start(),
+        );
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!start.is_finished());
+
+        let server = ServerBuilder::new(addr.to_string())
+            .port_ranges([PortRange::new(38300, 38399)])
+            .visitor_bind_ip(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .start()
+            .await
+            .unwrap();
+        let tunnel = start.await.unwrap().unwrap();
+        assert!((38300..=38399).contains(&tunnel.remote_port()));
+        assert!(tunnel.services()[0].last_error.is_none());
+
+        tunnel.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_elapsed() {
+        // 誰も待ち受けていないポートに再接続を続け、max_elapsed_secs を過ぎたらあきらめる
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let started = tokio::time::Instant::now();
+        let err = TunnelBuilder::new(addr.to_string())
+            .local_port(8080)
+            .no_proxy()
+            .retry_policy(RetryConfig {
+                initial_interval_ms: 100,
+                max_interval_ms: 200,
+                max_elapsed_secs: Some(1),
+                ..Default::default()
+            })
+            .start()
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::ConnectionRefused(_)));
+
+        // 次の待ち時間で上限を超える時点であきらめるので、最大の待ち時間（ばらつき込み）の分だけ早まりうる
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(700), "gave up after {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "gave up after {:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_on_version_mismatch() {
+        use crate::protocol::{ErrorCode, Message, PROTOCOL_VERSION};
+
+        // 別のバージョンを話すサーバーには再接続しても繋がらないので、すぐにあきらめる
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut conn, _)) = listener.accept().await {
+                let _ = Message::read_from(&mut conn).await;
+                let _ = Message::Hello {
+                    version: PROTOCOL_VERSION + 1,
+                    capabilities: vec![],
+                }
+                .write_to(&mut conn)
+                .await;
+            }
+        });
+
+        let err = tokio::time::timeout(
+            Duration::from_secs(5),
+            TunnelBuilder::new(addr.to_string())
+                .local_port(8080)
+                .no_proxy()
+                .retry_policy(RetryConfig {
+                    initial_interval_ms: 100,
+                    max_interval_ms: 200,
+                    ..Default::default()
+                })
+                .start(),
+        )
+        .await
+        .unwrap()
+        .err()
+        .unwrap();
+        assert!(!err.is_transient());
+        assert_eq!(err.rejection().unwrap().code, ErrorCode::UnsupportedVersion);
+    }
+}
@@ -0,0 +1,156 @@
+use anyhow::{Context, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// この時間どちらの方向にもデータグラムが流れなければフローを閉じる
+pub const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// フローごとに溜めておけるデータグラム数（あふれた分は捨てる）
+pub const UDP_FLOW_QUEUE: usize = 64;
+
+/// トンネルごとの同時フロー数の上限
+/// 送信元アドレスは偽装できるので、上限に達したら新しい送信元のデータグラムは捨てる
+pub const MAX_UDP_FLOWS: usize = 256;
+
+/// データグラムの最大サイズ
+pub const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// データグラムをデータチャネルに書き込む
+/// フォーマット: [length: u16 little-endian][payload]
+pub async fn write_datagram<W: AsyncWrite + Unpin>(writer: &mut W, data: &[u8]) -> Result<()> {
+    let len = u16::try_from(data.len()).context("Datagram too large")?;
+    let mut frame = Vec::with_capacity(2 + data.len());
+    frame.extend_from_slice(&len.to_le_bytes());
+    frame.extend_from_slice(data);
+    writer
+        .write_all(&frame)
+        .await
+        .context("Failed to write datagram")?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// データチャネルからデータグラムを1つ読み込む
+pub async fn read_datagram<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let len = reader
+        .read_u16_le()
+        .await
+        .context("Failed to read datagram length")?;
+    let mut buf = vec![0u8; len as usize];
+    reader
+        .read_exact(&mut buf)
+        .await
+        .context("Failed to read datagram")?;
+    Ok(buf)
+}
+
+/// データチャネルと UDP のフローを中継する
+/// `from_udp` で受け取ったデータグラムをデータチャネルへ、データチャネルから読んだものを
+/// `socket` へ送る（`peer` があれば `send_to`、なければ接続済みソケットとして `send`）
+/// どちらかが閉じるか、`UDP_IDLE_TIMEOUT` の間通信がなければ終了する
+pub async fn relay<D: AsyncRead + AsyncWrite + Send + 'static>(
+    data: D,
+    socket: Arc<UdpSocket>,
+    peer: Option<SocketAddr>,
+    mut from_udp: mpsc::Receiver<Vec<u8>>,
+) -> Result<()> {
+    let (mut read_half, mut write_half) = tokio::io::split(data);
+
+    // read_datagram はキャンセル安全ではないため別タスクで読む
+    let (frame_tx, mut frame_rx) = mpsc::channel(UDP_FLOW_QUEUE);
+    let reader = tokio::spawn(async move {
+        while let Ok(frame) = read_datagram(&mut read_half).await {
+            if frame_tx.send(frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let idle = tokio::time::sleep(UDP_IDLE_TIMEOUT);
+    tokio::pin!(idle);
+
+    let result = loop {
+        tokio::select! {
+            frame = frame_rx.recv() => {
+                let Some(frame) = frame else {
+                    debug!("Data channel closed");
+                    break Ok(());
+                };
+                idle.as_mut().reset(tokio::time::Instant::now() + UDP_IDLE_TIMEOUT);
+                let sent = match peer {
+                    Some(peer) => socket.send_to(&frame, peer).await,
+                    None => socket.send(&frame).await,
+                };
+                // UDP なので送れなかったデータグラムは捨てて続ける
+                if let Err(e) = sent {
+                    debug!("Failed to send datagram: {}", e);
+                }
+            }
+            datagram = from_udp.recv() => {
+                let Some(datagram) = datagram else {
+                    break Ok(());
+                };
+                idle.as_mut().reset(tokio::time::Instant::now() + UDP_IDLE_TIMEOUT);
+                if let Err(e) = write_datagram(&mut write_half, &datagram).await {
+                    break Err(e);
+                }
+            }
+            _ = &mut idle => {
+                debug!("UDP flow idle for {:?}, closing", UDP_IDLE_TIMEOUT);
+                break Ok(());
+            }
+        }
+    };
+
+    reader.abort();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_datagram_framing() {
+        let mut buf = Vec::new();
+        write_datagram(&mut buf, b"hello").await.unwrap();
+        write_datagram(&mut buf, b"").await.unwrap();
+        assert_eq!(&buf[..2], &5u16.to_le_bytes());
+
+        let mut reader = buf.as_slice();
+        assert_eq!(read_datagram(&mut reader).await.unwrap(), b"hello");
+        assert_eq!(read_datagram(&mut reader).await.unwrap(), b"");
+        assert!(read_datagram(&mut reader).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        let local = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+
+        let (data, mut remote) = tokio::io::duplex(1024);
+        let (udp_tx, udp_rx) = mpsc::channel(UDP_FLOW_QUEUE);
+        let handle = tokio::spawn(relay(data, local.clone(), Some(peer_addr), udp_rx));
+
+        // UDP -> データチャネル
+        udp_tx.send(b"ping".to_vec()).await.unwrap();
+        assert_eq!(read_datagram(&mut remote).await.unwrap(), b"ping");
+
+        // データチャネル -> UDP
+        write_datagram(&mut remote, b"pong").await.unwrap();
+        let mut buf = [0u8; 16];
+        let (len, from) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"pong");
+        assert_eq!(from, local.local_addr().unwrap());
+
+        // データチャネルが閉じたら終了
+        drop(remote);
+        handle.await.unwrap().unwrap();
+    }
+}